@@ -0,0 +1,245 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::env;
+
+// bump when the layout of the already known actions changes
+const VERSION: u8 = 1;
+// truncated HMAC, enough to make guessing hopeless while fitting the payload into 64 bytes
+const TAG_LEN: usize = 8;
+// telegram doesn't accept longer callback data
+const MAX_CALLBACK_DATA_LEN: usize = 64;
+
+const FULL_ALBUM: u8 = 1;
+const THREAD: u8 = 2;
+const SETTINGS: u8 = 3;
+const UNROLL: u8 = 4;
+const THREAD_POSITION: u8 = 5;
+
+lazy_static::lazy_static! {
+    static ref CALLBACK_SECRET: String = env::var("CALLBACK_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty())
+        .or_else(|| env::var("TELOXIDE_TOKEN").ok())
+        .unwrap_or_default();
+}
+
+/// What an inline keyboard button asks the bot to do. Encoded as
+/// `base64(version | action | fields | hmac)`, with the numbers in big endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallbackAction {
+    /// Send all the media of the tweet to the user privately.
+    FullAlbum {
+        tweet_id: u64,
+    },
+    /// Show the tweet of the thread at `position`, the conversation root being at 0.
+    Thread {
+        conversation_id: u64,
+        user_id: u64,
+        position: u16,
+    },
+    /// Toggle the setting with the index.
+    Settings {
+        setting: u8,
+    },
+    Unroll {
+        conversation_id: u64,
+        user_id: u64,
+    },
+    /// The position indicator of the thread keyboard, does nothing.
+    ThreadPosition,
+}
+
+fn signature(payload: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(CALLBACK_SECRET.as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(payload);
+    mac
+}
+
+fn take<const N: usize>(bytes: &mut &[u8]) -> Option<[u8; N]> {
+    if bytes.len() < N {
+        return None;
+    }
+
+    let (head, tail) = bytes.split_at(N);
+    *bytes = tail;
+    head.try_into().ok()
+}
+
+fn take_u64(bytes: &mut &[u8]) -> Option<u64> {
+    take(bytes).map(u64::from_be_bytes)
+}
+
+impl CallbackAction {
+    pub fn encode(&self) -> String {
+        let mut payload = vec![VERSION];
+
+        match *self {
+            CallbackAction::FullAlbum { tweet_id } => {
+                payload.push(FULL_ALBUM);
+                payload.extend_from_slice(&tweet_id.to_be_bytes());
+            }
+            CallbackAction::Thread {
+                conversation_id,
+                user_id,
+                position,
+            } => {
+                payload.push(THREAD);
+                payload.extend_from_slice(&conversation_id.to_be_bytes());
+                payload.extend_from_slice(&user_id.to_be_bytes());
+                payload.extend_from_slice(&position.to_be_bytes());
+            }
+            CallbackAction::Settings { setting } => {
+                payload.extend_from_slice(&[SETTINGS, setting]);
+            }
+            CallbackAction::Unroll {
+                conversation_id,
+                user_id,
+            } => {
+                payload.push(UNROLL);
+                payload.extend_from_slice(&conversation_id.to_be_bytes());
+                payload.extend_from_slice(&user_id.to_be_bytes());
+            }
+            CallbackAction::ThreadPosition => payload.push(THREAD_POSITION),
+        }
+
+        let tag = signature(&payload).finalize().into_bytes();
+        payload.extend_from_slice(&tag[..TAG_LEN]);
+
+        URL_SAFE_NO_PAD.encode(payload)
+    }
+
+    /// `None` for forged, broken or outdated data, the reason goes to the log.
+    pub fn decode(data: &str) -> Option<CallbackAction> {
+        if data.len() > MAX_CALLBACK_DATA_LEN {
+            log::warn!("callback data is too long");
+            return None;
+        }
+
+        let payload = match URL_SAFE_NO_PAD.decode(data) {
+            Ok(payload) if payload.len() > TAG_LEN + 1 => payload,
+            _ => {
+                // buttons sent before the data was signed end up here as well
+                log::warn!("malformed callback data {:?}", data);
+                return None;
+            }
+        };
+
+        let (payload, tag) = payload.split_at(payload.len() - TAG_LEN);
+
+        if signature(payload).verify_truncated_left(tag).is_err() {
+            log::warn!("callback data with a wrong signature {:?}", data);
+            return None;
+        }
+
+        let mut bytes = payload;
+        let [version, action] = take(&mut bytes)?;
+
+        if version != VERSION {
+            log::warn!("unsupported callback data version {}", version);
+            return None;
+        }
+
+        let action = match action {
+            FULL_ALBUM => CallbackAction::FullAlbum {
+                tweet_id: take_u64(&mut bytes)?,
+            },
+            THREAD => CallbackAction::Thread {
+                conversation_id: take_u64(&mut bytes)?,
+                user_id: take_u64(&mut bytes)?,
+                position: take(&mut bytes).map(u16::from_be_bytes)?,
+            },
+            SETTINGS => {
+                let [setting] = take(&mut bytes)?;
+                CallbackAction::Settings { setting }
+            }
+            UNROLL => CallbackAction::Unroll {
+                conversation_id: take_u64(&mut bytes)?,
+                user_id: take_u64(&mut bytes)?,
+            },
+            THREAD_POSITION => CallbackAction::ThreadPosition,
+            _ => {
+                log::warn!("unknown callback action {}", action);
+                return None;
+            }
+        };
+
+        Some(action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed(mut payload: Vec<u8>) -> String {
+        let tag = signature(&payload).finalize().into_bytes();
+        payload.extend_from_slice(&tag[..TAG_LEN]);
+        URL_SAFE_NO_PAD.encode(payload)
+    }
+
+    #[test]
+    fn actions_round_trip() {
+        for action in [
+            CallbackAction::FullAlbum {
+                tweet_id: 1234567890123456789,
+            },
+            CallbackAction::Thread {
+                conversation_id: 1234567890123456789,
+                user_id: 987654321,
+                position: 42,
+            },
+            CallbackAction::Settings { setting: 3 },
+            CallbackAction::Unroll {
+                conversation_id: 1234567890123456789,
+                user_id: 987654321,
+            },
+            CallbackAction::ThreadPosition,
+        ] {
+            assert_eq!(CallbackAction::decode(&action.encode()), Some(action));
+        }
+    }
+
+    #[test]
+    fn tampered_data_is_rejected() {
+        let data = CallbackAction::FullAlbum { tweet_id: 20 }.encode();
+        let mut payload = URL_SAFE_NO_PAD.decode(&data).unwrap();
+        // point the button at another tweet keeping the old signature
+        payload[9] ^= 1;
+
+        assert_eq!(
+            CallbackAction::decode(&URL_SAFE_NO_PAD.encode(payload)),
+            None
+        );
+    }
+
+    #[test]
+    fn other_version_is_rejected() {
+        let mut payload = vec![VERSION + 1, FULL_ALBUM];
+        payload.extend_from_slice(&20u64.to_be_bytes());
+
+        assert_eq!(CallbackAction::decode(&signed(payload)), None);
+    }
+
+    #[test]
+    fn too_long_data_is_rejected() {
+        let mut payload = vec![VERSION, SETTINGS, 1];
+        payload.resize(MAX_CALLBACK_DATA_LEN, 0);
+
+        assert!(signed(payload.clone()).len() > MAX_CALLBACK_DATA_LEN);
+        assert_eq!(CallbackAction::decode(&signed(payload)), None);
+    }
+
+    #[test]
+    fn thread_fits_into_callback_data() {
+        let data = CallbackAction::Thread {
+            conversation_id: u64::MAX,
+            user_id: u64::MAX,
+            position: u16::MAX,
+        }
+        .encode();
+
+        assert!(data.len() <= MAX_CALLBACK_DATA_LEN);
+    }
+}
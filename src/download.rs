@@ -0,0 +1,110 @@
+use std::{env, path::PathBuf, time::Duration};
+use teloxide::types::InputFile;
+use tokio::{fs, io::AsyncWriteExt};
+use twitter_video_dl::serde_schemes::Variant;
+
+use crate::errors::TwideoError;
+
+// telegram refuses bigger files uploaded by bots
+const MAX_UPLOAD_SIZE: u64 = 50 * 1024 * 1024;
+// and ignores bigger thumbnails
+const MAX_THUMBNAIL_SIZE: usize = 200 * 1024;
+
+/// Picks the highest bitrate variant that still fits into the bot upload limit, judging
+/// by the `Content-Length` the CDN reports.
+pub async fn pick_uploadable_variant(variants: &[Variant]) -> Option<Variant> {
+    let mut variants = variants.to_vec();
+    variants.sort_by_key(|variant| std::cmp::Reverse(variant.bit_rate.unwrap_or(0)));
+
+    let client = reqwest::Client::new();
+
+    for variant in variants {
+        let response = match client.head(&variant.url).send().await {
+            Ok(response) if response.status().is_success() => response,
+            _ => continue,
+        };
+
+        match response.content_length() {
+            Some(size) if size <= MAX_UPLOAD_SIZE => return Some(variant),
+            Some(size) => log::info!("{} is too big to upload ({} bytes)", variant.url, size),
+            None => continue,
+        }
+    }
+
+    None
+}
+
+/// Removes the file when dropped, unless the download went through.
+struct PartialDownload {
+    path: Option<PathBuf>,
+}
+
+impl PartialDownload {
+    fn keep(mut self) {
+        self.path = None;
+    }
+}
+
+impl Drop for PartialDownload {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Streams the variant into a temp file; the caller is responsible for removing it.
+pub async fn download_variant(variant: &Variant) -> Result<PathBuf, TwideoError> {
+    let mut response = reqwest::get(&variant.url).await?.error_for_status()?;
+
+    let path = env::temp_dir().join(format!("twideo_{}.mp4", rand::random::<u64>()));
+    // declared before the file so the file is closed by the time it's removed
+    let download = PartialDownload {
+        path: Some(path.clone()),
+    };
+    let mut file = fs::File::create(&path).await?;
+    let mut downloaded: u64 = 0;
+
+    while let Some(chunk) = response.chunk().await? {
+        downloaded += chunk.len() as u64;
+
+        if downloaded > MAX_UPLOAD_SIZE {
+            return Err(TwideoError::Upstream(
+                "Video is too big to upload".to_string(),
+            ));
+        }
+
+        file.write_all(&chunk).await?;
+    }
+
+    file.flush().await?;
+
+    download.keep();
+
+    Ok(path)
+}
+
+/// Downloads the small version of the video preview to upload it as the thumbnail.
+pub async fn fetch_thumbnail(url: &str) -> Option<InputFile> {
+    if url.is_empty() {
+        return None;
+    }
+
+    let response = reqwest::Client::new()
+        .get(url)
+        .query(&[("name", "small")])
+        .timeout(Duration::from_secs(5))
+        .send()
+        .await
+        .ok()?
+        .error_for_status()
+        .ok()?;
+
+    let thumbnail = response.bytes().await.ok()?;
+
+    if thumbnail.len() > MAX_THUMBNAIL_SIZE {
+        return None;
+    }
+
+    Some(InputFile::memory(thumbnail).file_name("thumb.jpg"))
+}
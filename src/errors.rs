@@ -0,0 +1,112 @@
+use chrono::Utc;
+use std::{error::Error, fmt, time::Duration};
+
+#[derive(Debug)]
+pub enum TwideoError {
+    NotFound,
+    Protected,
+    Deleted,
+    /// `reset` is the unix timestamp the upstream promised to accept requests again at.
+    RateLimited {
+        reset: Option<i64>,
+    },
+    Unauthorized,
+    Upstream(String),
+    Cache(String),
+    Telegram(String),
+    /// Telegram flood control, the request can be repeated after the wait.
+    TelegramRetryAfter(Duration),
+}
+
+impl TwideoError {
+    /// Reply shown to the user in place of the tweet.
+    pub fn user_message(&self) -> String {
+        match self {
+            TwideoError::NotFound => "🤷‍♂️ Tweet not found.".to_string(),
+            TwideoError::Protected => "🔒 This tweet is from a protected account.".to_string(),
+            TwideoError::Deleted => {
+                "🗑 This tweet was deleted or is not available anymore.".to_string()
+            }
+            TwideoError::RateLimited { reset } => {
+                let mut text = "🧑‍💻👨‍💻⚠️ Server is busy! Please try a little later.".to_string();
+                if let Some(reset) = reset {
+                    let minutes = (reset - Utc::now().timestamp()) / 60 + 1;
+                    if minutes > 0 {
+                        text.push_str(&format!(" (in about {} min)", minutes));
+                    }
+                }
+                text
+            }
+            TwideoError::Unauthorized => "☠️ Bot can't access Twitter at the moment.".to_string(),
+            TwideoError::Upstream(_) => {
+                "⚠️ Twitter didn't answer properly, please try again later.".to_string()
+            }
+            TwideoError::Cache(_) => "⚠️ Threads are unavailable right now.".to_string(),
+            TwideoError::Telegram(_) => "🤖 Telegram refused to send this tweet.".to_string(),
+            TwideoError::TelegramRetryAfter(_) => {
+                "🤖 Telegram asked to slow down, please try a little later.".to_string()
+            }
+        }
+    }
+
+    /// Whether another backend might still be able to answer the same request.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            TwideoError::RateLimited { .. } | TwideoError::Unauthorized | TwideoError::Upstream(_)
+        )
+    }
+}
+
+impl fmt::Display for TwideoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TwideoError::NotFound => write!(f, "Tweet not found"),
+            TwideoError::Protected => write!(f, "Tweet is protected"),
+            TwideoError::Deleted => write!(f, "Tweet is deleted"),
+            TwideoError::RateLimited { reset } => write!(f, "Rate limited until {:?}", reset),
+            TwideoError::Unauthorized => write!(f, "Unauthorized Error!"),
+            TwideoError::Upstream(err) => write!(f, "Upstream error: {}", err),
+            TwideoError::Cache(err) => write!(f, "Cache error: {}", err),
+            TwideoError::Telegram(err) => write!(f, "Telegram error: {}", err),
+            TwideoError::TelegramRetryAfter(wait) => {
+                write!(f, "Telegram flood control, retry after {:?}", wait)
+            }
+        }
+    }
+}
+
+impl Error for TwideoError {}
+
+impl From<reqwest::Error> for TwideoError {
+    fn from(err: reqwest::Error) -> Self {
+        TwideoError::Upstream(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for TwideoError {
+    fn from(err: serde_json::Error) -> Self {
+        TwideoError::Upstream(err.to_string())
+    }
+}
+
+impl From<std::io::Error> for TwideoError {
+    fn from(err: std::io::Error) -> Self {
+        TwideoError::Upstream(err.to_string())
+    }
+}
+
+impl From<redis::RedisError> for TwideoError {
+    fn from(err: redis::RedisError) -> Self {
+        TwideoError::Cache(err.to_string())
+    }
+}
+
+impl From<teloxide::RequestError> for TwideoError {
+    fn from(err: teloxide::RequestError) -> Self {
+        match err {
+            teloxide::RequestError::RetryAfter(wait) => TwideoError::TelegramRetryAfter(wait),
+            err => TwideoError::Telegram(err.to_string()),
+        }
+    }
+}
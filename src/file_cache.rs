@@ -0,0 +1,62 @@
+use crate::storage;
+use lru::LruCache;
+use redis::AsyncCommands;
+use std::{num::NonZeroUsize, sync::Mutex};
+
+const FILE_ID_KEY: &str = "file_id";
+const FILE_ID_TTL: u32 = 30 * 24 * 60 * 60;
+const MEMORY_CACHE_SIZE: usize = 1000;
+
+lazy_static::lazy_static! {
+    static ref MEMORY_CACHE: Mutex<LruCache<(u64, usize), String>> =
+        Mutex::new(LruCache::new(NonZeroUsize::new(MEMORY_CACHE_SIZE).unwrap()));
+}
+
+/// Telegram `file_id` of the media uploaded for the tweet before, so it can be re-sent
+/// without telegram downloading it again.
+pub async fn get_file_id(tweet_id: u64, media_index: usize) -> Option<String> {
+    if !storage::is_configured() {
+        return MEMORY_CACHE
+            .lock()
+            .ok()?
+            .get(&(tweet_id, media_index))
+            .cloned();
+    }
+
+    let mut con = storage::connection().await.ok()?;
+    let redis_key = format!("{}:{}", FILE_ID_KEY, tweet_id);
+
+    storage::run(con.hget(redis_key, media_index)).await.ok()?
+}
+
+pub async fn save_file_id(tweet_id: u64, media_index: usize, file_id: &str) {
+    if !storage::is_configured() {
+        if let Ok(mut cache) = MEMORY_CACHE.lock() {
+            cache.put((tweet_id, media_index), file_id.to_string());
+        }
+        return;
+    }
+
+    let mut con = match storage::connection().await {
+        Ok(con) => con,
+        Err(err) => {
+            log::warn!("failed to cache file id: {}", err);
+            return;
+        }
+    };
+
+    let redis_key = format!("{}:{}", FILE_ID_KEY, tweet_id);
+    let mut pipe = redis::pipe();
+
+    pipe.cmd("HSET")
+        .arg(redis_key.clone())
+        .arg(media_index)
+        .arg(file_id);
+    pipe.cmd("EXPIRE").arg(redis_key).arg(FILE_ID_TTL);
+
+    let result: Result<(), _> = storage::run(pipe.query_async(&mut con)).await;
+
+    if let Err(err) = result {
+        log::warn!("failed to cache file id: {}", err);
+    }
+}
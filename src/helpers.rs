@@ -1,362 +1,1072 @@
 extern crate lazy_static;
 extern crate redis;
 
-use rand::seq::SliceRandom;
-use redis::Commands;
+use crate::{
+    errors::TwideoError,
+    file_cache, metrics,
+    settings::{ChatSettings, VideoQuality},
+    sources, storage,
+    tweet_cache::{self, CachedTweet, MissingTweet},
+};
+use chrono::DateTime;
+use redis::AsyncCommands;
 use regex::Regex;
-use std::{env, error::Error, fmt};
+use serde::{Deserialize, Serialize};
+use std::env;
+use teloxide::utils::html;
 use twitter_video_dl::serde_schemes::*;
 
 const DISABLED: &str = "disabled";
 
 lazy_static::lazy_static! {
-    static ref TWITTER_STATUS_URL: &'static str = "https://api.twitter.com/1.1/statuses/show.json?extended_entities=true&tweet_mode=extended&id=";
-    static ref TWITTER_V2_URL: &'static str = "https://api.twitter.com/2/tweets?expansions=author_id&ids=";
-
-    static ref TWITTER_BEARER_TOKENS: Vec<String> = vec![
-        env::var("TWITTER_BEARER_TOKEN").unwrap_or_else(|_| "".to_string()),
-        env::var("TWITTER_BEARER_TOKEN2").unwrap_or_else(|_| "".to_string())
-    ].into_iter().filter(|x| !x.is_empty()).collect::<Vec<String>>();
-
-    static ref TWITTER_MULTIMEDIA_URL: &'static str = "https://api.twitter.com/2/tweets";
-    static ref TWITTER_SEARCH_URL: &'static str = "https://api.twitter.com/2/tweets/search/recent";
-    static ref TWITTER_EXPANSIONS_PARAMS: &'static str = "expansions=attachments.media_keys,author_id&media.fields=url,variants,preview_image_url&user.fields=name";
-    static ref RE : regex::Regex= Regex::new("https://t.co/\\w+\\b").unwrap();
-    static ref REDIS_URL: String = env::var("REDIS_URL").unwrap_or_else(|_| "".to_string());
+    static ref SHORT_LINK_RE: Regex = Regex::new(r"https://t\.co/\w+\b").unwrap();
+    static ref MENTION_RE: Regex = Regex::new(r"\B@\w+").unwrap();
+    static ref HASHTAG_RE: Regex = Regex::new(r"\B#\w+").unwrap();
     static ref THREADS_SUPPORT: String = env::var("THREADS_SUPPORT").unwrap_or_else(|_| DISABLED.to_string());
 }
 
-pub fn get_twitter_id(link: &str) -> TwitterID {
-    if link.contains("twitter.com/i/spaces/") {
-        return TwitterID::None;
-    }
-
-    let parsed: Vec<&str> = (link[20..]).split('/').collect();
-    let last_parts: Vec<&str> = parsed.last().unwrap().split('?').collect();
-    let possible_id = last_parts.first().unwrap().parse().unwrap_or(0);
-
-    if possible_id > 0 {
-        TwitterID::Id(possible_id)
-    } else {
-        TwitterID::None
-    }
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TwitterMedia {
+    pub url: String,
+    pub r#type: String,
+    pub thumb: String,
+    pub file_id: Option<String>,
+    /// mp4 variants of the video ordered by bitrate, the best one first.
+    pub variants: Vec<Variant>,
+    pub metadata: VideoMetadata,
+    /// Image description written by the author.
+    pub alt_text: Option<String>,
 }
 
-#[derive(Debug)]
-struct MyError(String);
+/// Lets telegram show the right placeholder and duration before the video is loaded.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct VideoMetadata {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// In seconds.
+    pub duration: Option<u32>,
+}
 
-impl fmt::Display for MyError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "There is an error: {}", self.0)
+impl TwitterMedia {
+    /// Url of the video variant matching the quality the chat asked for.
+    pub fn url_for(&self, quality: VideoQuality) -> &str {
+        self.variants_for(quality)
+            .first()
+            .map_or(self.url.as_str(), |variant| variant.url.as_str())
     }
-}
 
-impl Error for MyError {}
+    /// Variants no better than the quality the chat asked for, the best one first.
+    pub fn variants_for(&self, quality: VideoQuality) -> &[Variant] {
+        let start = match quality {
+            VideoQuality::Best => 0,
+            VideoQuality::Medium => self.variants.len() / 2,
+            VideoQuality::Low => self.variants.len().saturating_sub(1),
+        };
 
-#[derive(Debug)]
-pub struct TwitterMedia {
-    pub url: String,
-    pub r#type: String,
-    pub thumb: String,
+        &self.variants[start..]
+    }
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TwitDetails {
     pub caption: String,
     pub twitter_media: Vec<TwitterMedia>,
     pub name: String,
+    pub username: String,
     pub id: u64,
-    pub extra_urls: Vec<Variant>,
     pub conversation_id: u64,
-    pub next: u8,
+    /// Position of the tweet in its thread plus one, the root being at 0. Zero when the
+    /// tweet belongs to the conversation but isn't on the thread chain.
+    pub next: u16,
     pub user_id: u64,
     pub thread_count: usize,
+    pub quoted: Option<Box<ReferencedTweet>>,
+    pub replied_to: Option<Box<ReferencedTweet>>,
+    pub poll: Option<TwitterPoll>,
+    pub card: Option<TwitterCard>,
 }
 
-pub enum TwitterID {
-    Id(u64),
-    None,
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TwitterPollOption {
+    pub label: String,
+    pub votes: u64,
 }
 
-pub async fn get_twitter_data(
-    twitter_id: u64,
-) -> Result<Option<TwitDetails>, Box<dyn std::error::Error>> {
-    log::info!("Send request to twitter");
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TwitterPoll {
+    pub options: Vec<TwitterPollOption>,
+    pub open: bool,
+    pub end_datetime: Option<String>,
+}
 
-    let token = TWITTER_BEARER_TOKENS
-        .choose(&mut rand::thread_rng())
-        .unwrap()
-        .to_string();
+/// Preview twitter shows for the link in the tweet.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TwitterCard {
+    pub url: String,
+    pub title: String,
+    pub description: Option<String>,
+    pub image: Option<String>,
+}
 
-    let client = reqwest::Client::new();
+/// Quoted tweet or the one being replied to, shown along with the tweet.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReferencedTweet {
+    pub id: u64,
+    pub text: String,
+    pub name: String,
+    pub username: String,
+    pub twitter_media: Vec<TwitterMedia>,
+}
 
-    let multimedia_response = client
-        .get(format!(
-            "{}/{}?tweet.fields=conversation_id&{}",
-            &*TWITTER_MULTIMEDIA_URL, twitter_id, &*TWITTER_EXPANSIONS_PARAMS
-        ))
-        .header("AUTHORIZATION", format!("Bearer {}", token))
-        .send()
-        .await?;
+impl TwitDetails {
+    /// Everything that goes to the album: media of the tweet followed by the quoted media.
+    pub fn album(&self) -> impl Iterator<Item = &TwitterMedia> {
+        self.twitter_media
+            .iter()
+            .chain(self.quoted.iter().flat_map(|quoted| &quoted.twitter_media))
+    }
 
-    log::info!("Status {}", multimedia_response.status().as_u16());
+    fn album_mut(&mut self) -> impl Iterator<Item = &mut TwitterMedia> {
+        self.twitter_media.iter_mut().chain(
+            self.quoted
+                .iter_mut()
+                .flat_map(|quoted| &mut quoted.twitter_media),
+        )
+    }
+}
 
-    if multimedia_response.status().as_u16() == 401 {
-        return Err(Box::new(MyError("Unauthorized Error!".into())));
+/// Picks the highest bitrate variant for the video and keeps every other mp4 variant
+/// as a fallback for the cases telegram refuses the best one.
+pub fn push_video(
+    twitter_media: &mut Vec<TwitterMedia>,
+    r#type: &str,
+    variants: &[Variant],
+    thumb: &str,
+    metadata: VideoMetadata,
+) {
+    let mut last_bitrate = 0;
+    let mut last_url = "";
+    let mut alternative_url = "";
+    let mut mp4_variants = Vec::new();
+
+    for variant in variants {
+        if let Some(bitrate) = variant.bit_rate {
+            mp4_variants.push(variant.clone());
+            if bitrate >= last_bitrate {
+                last_url = variant.url.as_str();
+                last_bitrate = bitrate;
+            }
+        } else {
+            alternative_url = variant.url.as_str();
+        }
     }
 
-    if multimedia_response.status().as_u16() == 429 {
-        return Ok(None);
+    mp4_variants.sort_by_key(|variant| std::cmp::Reverse(variant.bit_rate));
+
+    if !last_url.is_empty() {
+        twitter_media.push(TwitterMedia {
+            url: last_url.to_string(),
+            r#type: r#type.to_string(),
+            thumb: thumb.to_string(),
+            file_id: None,
+            variants: mp4_variants,
+            metadata,
+            alt_text: None,
+        });
+    } else if !alternative_url.is_empty() {
+        twitter_media.push(TwitterMedia {
+            url: alternative_url.to_string(),
+            r#type: r#type.to_string(),
+            thumb: thumb.to_string(),
+            file_id: None,
+            variants: mp4_variants,
+            metadata,
+            alt_text: None,
+        });
     }
+}
 
-    let multimedia = multimedia_response.json::<MultimediaBody>().await?;
+pub fn push_photo(twitter_media: &mut Vec<TwitterMedia>, url: &str, alt_text: Option<&str>) {
+    twitter_media.push(TwitterMedia {
+        url: url.to_string(),
+        r#type: "photo".to_string(),
+        thumb: url.to_string(),
+        file_id: None,
+        variants: vec![],
+        metadata: VideoMetadata::default(),
+        alt_text: alt_text
+            .filter(|alt_text| !alt_text.is_empty())
+            .map(str::to_string),
+    });
+}
 
-    let mut twitter_media: Vec<TwitterMedia> = Vec::new();
-    let mut extra_urls: Vec<Variant> = Vec::new();
-    let mut name = String::new();
-    let mut username = String::new();
-    let conversation_id = multimedia
-        .data
-        .conversation_id
-        .unwrap()
-        .parse::<u64>()
-        .unwrap();
-    let user_id = multimedia.data.author_id.unwrap().parse::<u64>().unwrap();
+// how far the escaping can move an entity from the offset the API reports for it
+const MAX_ENTITY_DRIFT: usize = 100;
 
-    let thread_count = if *THREADS_SUPPORT == DISABLED {
-        0
-    } else {
-        fetch_threads(conversation_id, user_id).await
-    };
+/// Piece of the tweet text rendered as a link. `token` is how it's written in the text,
+/// give or take the letter case.
+struct TextEntity {
+    /// Byte offset in the unescaped text where the entity is expected.
+    position: usize,
+    token: String,
+    kind: TextEntityKind,
+}
+
+enum TextEntityKind {
+    Url {
+        expanded_url: String,
+        display_url: String,
+    },
+    /// Links to the attached media or to the quoted tweet, they are shown by other means.
+    Hidden,
+    Mention,
+    Hashtag,
+    Cashtag,
+}
+
+/// Decodes the html entities twitter, nitter and our own captions use. Twitter escapes
+/// the tweet text, but not the entities.
+pub fn unescape_html(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
 
-    if let Some(includes) = &multimedia.includes {
-        name = includes.users[0].name.to_string();
-        username = includes.users[0].username.to_string();
-
-        if let Some(media_set) = &includes.media {
-            for media in media_set {
-                if media.r#type == "video" || media.r#type == "animated_gif" {
-                    let mut last_bitrate = 0;
-                    let mut last_url = "";
-                    let mut alternative_url = "";
-
-                    for variant in media.variants.as_ref().unwrap() {
-                        if let Some(bitrate) = variant.bit_rate {
-                            extra_urls.push(variant.clone());
-                            if bitrate >= last_bitrate {
-                                last_url = variant.url.as_str();
-                                last_bitrate = bitrate;
-                            }
-                        } else {
-                            alternative_url = variant.url.as_str();
-                        }
-                    }
-
-                    if !last_url.is_empty() {
-                        twitter_media.push(TwitterMedia {
-                            url: last_url.to_string(),
-                            r#type: media.r#type.to_string(),
-                            thumb: media.preview_image_url.as_ref().unwrap().to_owned(),
-                        });
-                    } else if !alternative_url.is_empty() {
-                        twitter_media.push(TwitterMedia {
-                            url: alternative_url.to_string(),
-                            r#type: media.r#type.to_string(),
-                            thumb: media.preview_image_url.as_ref().unwrap().to_owned(),
-                        });
-                    }
-                } else if media.r#type == "photo" {
-                    let _url = media.url.as_ref().unwrap().to_string();
-                    twitter_media.push(TwitterMedia {
-                        url: _url.to_string(),
-                        r#type: media.r#type.to_string(),
-                        thumb: _url,
-                    });
+/// Entity offsets are counted in code points.
+fn byte_offset(text: &str, position: usize) -> usize {
+    text.char_indices()
+        .nth(position)
+        .map_or(text.len(), |(offset, _)| offset)
+}
+
+fn tweet_entities(text: &str, entities: &Entities, quoted_id: Option<u64>) -> Vec<TextEntity> {
+    let quote_path = quoted_id.map(|id| format!("/status/{}", id));
+    let mut text_entities = Vec::new();
+
+    for url in &entities.urls {
+        let expanded_url = url.expanded_url.as_deref().unwrap_or(&url.url);
+        let is_quote = quote_path
+            .as_ref()
+            .is_some_and(|path| expanded_url.ends_with(path.as_str()));
+
+        text_entities.push(TextEntity {
+            position: byte_offset(text, url.start),
+            token: url.url.to_string(),
+            kind: if url.media_key.is_some() || is_quote {
+                TextEntityKind::Hidden
+            } else {
+                TextEntityKind::Url {
+                    expanded_url: expanded_url.to_string(),
+                    display_url: url
+                        .display_url
+                        .as_deref()
+                        .unwrap_or(expanded_url)
+                        .to_string(),
                 }
+            },
+        });
+    }
+
+    for mention in &entities.mentions {
+        text_entities.push(TextEntity {
+            position: byte_offset(text, mention.start),
+            token: format!("@{}", mention.username),
+            kind: TextEntityKind::Mention,
+        });
+    }
+
+    for hashtag in &entities.hashtags {
+        text_entities.push(TextEntity {
+            position: byte_offset(text, hashtag.start),
+            token: format!("#{}", hashtag.tag),
+            kind: TextEntityKind::Hashtag,
+        });
+    }
+
+    for cashtag in &entities.cashtags {
+        text_entities.push(TextEntity {
+            position: byte_offset(text, cashtag.start),
+            token: format!("${}", cashtag.tag),
+            kind: TextEntityKind::Cashtag,
+        });
+    }
+
+    text_entities.sort_by_key(|entity| entity.position);
+    text_entities
+}
+
+/// Sources without entities get them guessed from the text. The last t.co link is the
+/// media (or quoted tweet) one when there is any.
+fn detect_entities(text: &str, has_attachment: bool) -> Vec<TextEntity> {
+    let mut text_entities = Vec::new();
+    let short_links: Vec<_> = SHORT_LINK_RE.find_iter(text).collect();
+
+    for (i, short_link) in short_links.iter().enumerate() {
+        text_entities.push(TextEntity {
+            position: short_link.start(),
+            token: short_link.as_str().to_string(),
+            kind: if has_attachment && i == short_links.len() - 1 {
+                TextEntityKind::Hidden
+            } else {
+                TextEntityKind::Url {
+                    expanded_url: short_link.as_str().to_string(),
+                    display_url: short_link.as_str().to_string(),
+                }
+            },
+        });
+    }
+
+    for mention in MENTION_RE.find_iter(text) {
+        text_entities.push(TextEntity {
+            position: mention.start(),
+            token: mention.as_str().to_string(),
+            kind: TextEntityKind::Mention,
+        });
+    }
+
+    for hashtag in HASHTAG_RE.find_iter(text) {
+        text_entities.push(TextEntity {
+            position: hashtag.start(),
+            token: hashtag.as_str().to_string(),
+            kind: TextEntityKind::Hashtag,
+        });
+    }
+
+    text_entities.sort_by_key(|entity| entity.position);
+    text_entities
+}
+
+/// Where the entity is in the text after `cursor`. Offsets differ between the APIs (and the
+/// text is html escaped while they are not), so the token is looked up near its offset,
+/// ignoring the case as twitter reports usernames the way the account spells them.
+fn find_entity(text: &str, cursor: usize, entity: &TextEntity) -> Option<usize> {
+    let mut end = (entity.position + entity.token.len() + MAX_ENTITY_DRIFT).min(text.len());
+    while !text.is_char_boundary(end) {
+        end += 1;
+    }
+    let window = text.get(cursor..end)?.to_ascii_lowercase();
+
+    window
+        .match_indices(&entity.token.to_ascii_lowercase())
+        .map(|(offset, _)| cursor + offset)
+        .min_by_key(|start| start.abs_diff(entity.position))
+}
+
+/// Renders the tweet text as telegram html: escapes it, expands t.co links, links mentions
+/// and hashtags and drops the links to the media and the quoted tweet.
+pub fn build_caption(
+    tweet_text: &str,
+    entities: Option<&Entities>,
+    has_media: bool,
+    quoted_id: Option<u64>,
+) -> String {
+    let text = unescape_html(tweet_text);
+    let text_entities = match entities {
+        Some(entities) => tweet_entities(&text, entities, quoted_id),
+        None => detect_entities(&text, has_media || quoted_id.is_some()),
+    };
+
+    let mut caption = String::new();
+    let mut cursor = 0;
+
+    for entity in text_entities {
+        let start = match find_entity(&text, cursor, &entity) {
+            Some(start) => start,
+            None => continue,
+        };
+        let end = start + entity.token.len();
+        let written = &text[start..end];
+
+        caption.push_str(&html::escape(&text[cursor..start]));
+
+        match entity.kind {
+            TextEntityKind::Url {
+                expanded_url,
+                display_url,
+            } => caption.push_str(&html::link(&expanded_url, &display_url)),
+            TextEntityKind::Hidden => (),
+            TextEntityKind::Mention => caption.push_str(&html::link(
+                &format!("https://twitter.com/{}", &entity.token[1..]),
+                written,
+            )),
+            TextEntityKind::Hashtag => caption.push_str(&html::link(
+                &format!("https://twitter.com/hashtag/{}", &entity.token[1..]),
+                written,
+            )),
+            TextEntityKind::Cashtag => caption.push_str(&html::link(
+                &format!("https://twitter.com/search?q=%24{}", &entity.token[1..]),
+                written,
+            )),
+        }
+
+        cursor = end;
+    }
+
+    caption.push_str(&html::escape(&text[cursor..]));
+    caption.trim().to_string()
+}
+
+pub fn author_link(twitter_data: &TwitDetails) -> String {
+    format!(
+        "<a href='https://twitter.com/{}/status/{}'>&#x1F464 {}</a>",
+        twitter_data.username,
+        twitter_data.id,
+        html::escape(&twitter_data.name)
+    )
+}
+
+fn reference_block(icon: &str, tweet: &ReferencedTweet) -> String {
+    format!(
+        "<blockquote>{} <a href='https://twitter.com/{}/status/{}'>{}</a>:\n{}</blockquote>",
+        icon,
+        tweet.username,
+        tweet.id,
+        html::escape(&tweet.name),
+        tweet.text
+    )
+}
+
+const POLL_BAR_WIDTH: u64 = 10;
+
+fn poll_block(poll: &TwitterPoll) -> String {
+    let total: u64 = poll.options.iter().map(|option| option.votes).sum();
+
+    let status = match (&poll.end_datetime, poll.open) {
+        (Some(end_datetime), true) => match DateTime::parse_from_rfc3339(end_datetime) {
+            Ok(end) => format!("ends {}", end.format("%Y-%m-%d %H:%M UTC")),
+            Err(_) => "open".to_string(),
+        },
+        (None, true) => "open".to_string(),
+        (_, false) => "final results".to_string(),
+    };
+
+    let mut block = format!("<blockquote>📊 {} votes · {}", total, status);
+
+    for option in &poll.options {
+        let percent = (option.votes * 100).checked_div(total).unwrap_or(0);
+        let filled = (percent * POLL_BAR_WIDTH / 100) as usize;
+
+        block.push_str(&format!(
+            "\n{}{} {}% {}",
+            "█".repeat(filled),
+            "░".repeat(POLL_BAR_WIDTH as usize - filled),
+            percent,
+            html::escape(&option.label)
+        ));
+    }
+
+    block.push_str("</blockquote>");
+    block
+}
+
+fn card_block(card: &TwitterCard) -> String {
+    let mut block = format!(
+        "<blockquote>🔗 <a href=\"{}\"><b>{}</b></a>",
+        html::escape(&card.url),
+        html::escape(&card.title)
+    );
+
+    if let Some(description) = &card.description {
+        block.push('\n');
+        block.push_str(&html::escape(description));
+    }
+
+    block.push_str("</blockquote>");
+    block
+}
+
+/// Caption for the media message built according to the chat settings.
+/// Tweets without media always keep their text, there is nothing else to show.
+pub fn compose_caption(twitter_data: &TwitDetails, settings: &ChatSettings) -> String {
+    let mut caption = String::new();
+
+    if settings.caption || twitter_data.album().next().is_none() {
+        if let Some(replied_to) = &twitter_data.replied_to {
+            caption.push_str(&reference_block("↩️", replied_to));
+            caption.push('\n');
+        }
+
+        caption.push_str(&twitter_data.caption);
+
+        if let Some(quoted) = &twitter_data.quoted {
+            caption.push('\n');
+            caption.push_str(&reference_block("💬", quoted));
+        }
+
+        if let Some(poll) = &twitter_data.poll {
+            caption.push('\n');
+            caption.push_str(&poll_block(poll));
+        }
+
+        if let Some(card) = &twitter_data.card {
+            caption.push('\n');
+            caption.push_str(&card_block(card));
+        }
+    }
+
+    if settings.alt_text {
+        let alt_texts: Vec<String> = twitter_data
+            .album()
+            .enumerate()
+            .filter_map(|(i, media)| {
+                let alt_text = media.alt_text.as_ref()?;
+                Some(format!("🖼 {}: {}", i + 1, html::escape(alt_text)))
+            })
+            .collect();
+
+        if !alt_texts.is_empty() {
+            if !caption.is_empty() {
+                caption.push('\n');
             }
+            caption.push_str(&format!(
+                "<blockquote expandable>{}</blockquote>",
+                alt_texts.join("\n")
+            ));
         }
     }
 
-    let mut clean_caption = None;
-    let tweet_text = multimedia.data.text.as_ref().unwrap();
+    if settings.author_link {
+        if !caption.is_empty() {
+            caption.push_str(" \n\n");
+        }
+        caption.push_str(&author_link(twitter_data));
+    }
+
+    caption
+}
+
+/// Telegram limits the captions and messages by their visible length, the html markup
+/// doesn't count. Lengths are in UTF-16 code units, like telegram counts them.
+pub const CAPTION_LIMIT: usize = 1024;
+pub const MESSAGE_LIMIT: usize = 4096;
+
+pub fn visible_len(html: &str) -> usize {
+    let mut len = 0;
+    let mut in_tag = false;
+    let mut in_entity = false;
+
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if in_tag => (),
+            '&' => {
+                in_entity = true;
+                len += 1;
+            }
+            ';' if in_entity => in_entity = false,
+            _ if in_entity && (c.is_ascii_alphanumeric() || c == '#') => (),
+            _ => {
+                in_entity = false;
+                len += c.len_utf16();
+            }
+        }
+    }
+
+    len
+}
+
+// tags that may be split in two, closing them at the end of a chunk and opening again
+// at the start of the next one
+const BLOCK_TAGS: [&str; 1] = ["blockquote"];
+
+fn tag_name(tag: &str) -> &str {
+    tag.trim_start_matches('<')
+        .trim_start_matches('/')
+        .split(|c: char| c.is_whitespace() || c == '>')
+        .next()
+        .unwrap_or_default()
+}
 
-    let captures: Vec<&str> = RE
-        .captures_iter(tweet_text)
-        .map(|c| c.get(0).unwrap().as_str())
-        .collect();
+/// Splits the html text into chunks: the first one fits `first_limit`, the rest fit
+/// `MESSAGE_LIMIT`. It breaks only on whitespace outside of the inline html tags, block
+/// tags cut in two are closed and reopened, so every chunk stays valid html. A single
+/// word or link longer than the limit is left as is.
+pub fn split_html(html: &str, first_limit: usize) -> Vec<String> {
+    if visible_len(html) <= first_limit {
+        return vec![html.to_string()];
+    }
 
-    if !captures.is_empty() {
-        let mut captured = captures[captures.len() - 1];
+    // pieces are words with the whitespace that follows them, along with the opening
+    // tags of the blocks still open after the piece
+    let mut pieces: Vec<(&str, Vec<&str>)> = Vec::new();
+    let mut blocks: Vec<&str> = Vec::new();
+    let mut inline_depth: i32 = 0;
+    let mut piece_start = 0;
+    let mut chars = html.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        if c == '<' {
+            let end = html[i..].find('>').map_or(html.len(), |end| i + end + 1);
+            let tag = &html[i..end];
+            let is_block = BLOCK_TAGS.contains(&tag_name(tag));
+
+            match (tag.starts_with("</"), is_block) {
+                (true, true) => {
+                    blocks.pop();
+                }
+                (true, false) => inline_depth -= 1,
+                (false, true) => blocks.push(tag),
+                (false, false) => inline_depth += 1,
+            }
 
-        // means tweet doesn's contain media, so the link is real link (not media link)
-        if twitter_media.is_empty() {
-            clean_caption = Some(tweet_text.replace(captured, &format!("\n{}", captured)));
+            while chars.next_if(|(j, _)| *j < end).is_some() {}
+        } else if c.is_whitespace() && inline_depth <= 0 {
+            let next_is_word = chars.peek().is_some_and(|(_, next)| !next.is_whitespace());
+            if next_is_word {
+                let end = i + c.len_utf8();
+                pieces.push((&html[piece_start..end], blocks.clone()));
+                piece_start = end;
+            }
+        }
+    }
+    pieces.push((&html[piece_start..], blocks));
+
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    let mut chunk_len = 0;
+    let mut open_blocks: Vec<&str> = Vec::new();
+
+    for (piece, blocks) in pieces {
+        let limit = if chunks.is_empty() {
+            first_limit
         } else {
-            clean_caption = Some(tweet_text.replace(captured, "")); // remove media link
-            if captures.len() > 1 {
-                captured = captures[captures.len() - 2];
-                clean_caption = Some(
-                    clean_caption
-                        .as_ref()
-                        .unwrap()
-                        .replace(captured, &format!("\n{}", captured)),
-                );
+            MESSAGE_LIMIT
+        };
+        let piece_len = visible_len(piece);
+
+        if chunk_len + piece_len > limit && chunk_len > 0 {
+            let mut full_chunk = chunk.trim_end().to_string();
+            for tag in open_blocks.iter().rev() {
+                full_chunk.push_str(&format!("</{}>", tag_name(tag)));
             }
+            chunks.push(full_chunk);
+            chunk = open_blocks.concat();
+            chunk_len = 0;
+        }
+
+        chunk.push_str(piece);
+        chunk_len += piece_len;
+        open_blocks = blocks;
+    }
+
+    if chunk_len > 0 {
+        chunks.push(chunk.trim_end().to_string());
+    }
+
+    chunks
+}
+
+async fn fetch_and_cache_tweet(twitter_id: u64) -> Result<TwitDetails, TwideoError> {
+    match sources::fetch_tweet(twitter_id).await {
+        Ok(twitter_data) => {
+            tweet_cache::put(
+                twitter_id,
+                &CachedTweet::Found(Box::new(twitter_data.clone())),
+            )
+            .await;
+            Ok(twitter_data)
         }
+        Err(err) => {
+            let missing = match err {
+                TwideoError::NotFound => MissingTweet::NotFound,
+                TwideoError::Deleted => MissingTweet::Deleted,
+                TwideoError::Protected => MissingTweet::Protected,
+                _ => return Err(err),
+            };
+
+            tweet_cache::put(twitter_id, &CachedTweet::Missing(missing)).await;
+            Err(err)
+        }
+    }
+}
+
+async fn lookup_tweet(twitter_id: u64) -> Result<TwitDetails, TwideoError> {
+    match tweet_cache::get(twitter_id).await {
+        Some(CachedTweet::Found(twitter_data)) => Ok(*twitter_data),
+        Some(CachedTweet::Missing(missing)) => Err(missing.into()),
+        None => fetch_and_cache_tweet(twitter_id).await,
+    }
+}
+
+pub async fn get_twitter_data(twitter_id: u64) -> Result<TwitDetails, TwideoError> {
+    log::info!("Send request to twitter");
+
+    let mut twitter_data = lookup_tweet(twitter_id).await?;
+
+    for (i, media) in twitter_data.album_mut().enumerate() {
+        media.file_id = file_cache::get_file_id(twitter_id, i).await;
     }
 
-    Ok(Some(TwitDetails {
-        caption: format!(
-            "{} \n\n<a href='https://twitter.com/{}/status/{}'>&#x1F464 {}</a>",
-            || -> &str {
-                if clean_caption.is_none() {
-                    return tweet_text;
+    twitter_data.thread_count = if *THREADS_SUPPORT == DISABLED {
+        0
+    } else {
+        fetch_threads(
+            twitter_data.conversation_id,
+            twitter_data.user_id,
+            Some(&twitter_data),
+        )
+        .await
+        .unwrap_or_else(|err| {
+            log::warn!("failed to fetch threads: {}", err);
+            0
+        })
+    };
+
+    // the tweet may be shared from the middle of the thread, and the root is off a partial one
+    if twitter_data.thread_count > 0 {
+        twitter_data.next =
+            match thread_position(twitter_data.conversation_id, twitter_data.id).await {
+                Ok(Some(position)) => position + 1,
+                Ok(None) => 0,
+                Err(err) => {
+                    log::warn!("failed to find tweet in thread: {}", err);
+                    0
                 }
-                return clean_caption.as_ref().unwrap();
-            }(),
-            username,
-            twitter_id,
-            name
-        ),
-        twitter_media,
-        name,
-        id: twitter_id,
-        extra_urls,
-        next: 1,
-        conversation_id,
-        thread_count,
-        user_id,
-    }))
+            };
+    }
+
+    Ok(twitter_data)
 }
 
 const CONVERSATION_KEY: &str = "conversation";
-const EXPIRE_KEY_TTL: u32 = 24 * 60 * 60;
+// threads are searched again after a day to pick up the new tweets of the thread
+const REFRESH_KEY_TTL: u32 = 24 * 60 * 60;
+// but the last known thread is kept much longer, as neither the search nor the walk can
+// find old threads from the buttons pressed later on
+const EXPIRE_KEY_TTL: u32 = 30 * 24 * 60 * 60;
+// as long as the longest thread the search finds, every step of the walk is a tweet
+// lookup though, so it's cached along the way
+const MAX_THREAD_WALK: usize = 1000;
+
+/// Reconstructs the thread up to `twitter_data` following its `replied_to` tweets, for
+/// threads the search doesn't know about (it only covers the last seven days). Returns
+/// the thread without the conversation root, or nothing if the tweet isn't a part of the
+/// author's thread. When the walk is cut short the tweets found so far are returned
+/// marked as partial: they don't reach the root, so the thread starts at the first of them.
+async fn walk_thread_up(twitter_data: &TwitDetails) -> (Vec<u64>, bool) {
+    let mut partial = false;
+    let mut thread_ids = vec![];
+    let mut current = twitter_data.clone();
+
+    while current.id != twitter_data.conversation_id {
+        if thread_ids.len() >= MAX_THREAD_WALK {
+            log::warn!(
+                "thread {} is too long to walk",
+                twitter_data.conversation_id
+            );
+            partial = true;
+            break;
+        }
 
-async fn fetch_threads(conversation_id: u64, user_id: u64) -> usize {
-    // check cache if fetch threads before
-    let client = redis::Client::open(&**REDIS_URL);
+        let parent_id = match &current.replied_to {
+            Some(parent) if parent.username.eq_ignore_ascii_case(&twitter_data.username) => {
+                parent.id
+            }
+            // a reply to someone else, so it isn't a thread of the author
+            _ => return (vec![], false),
+        };
+
+        thread_ids.push(current.id);
 
-    if client.is_err() {
-        return 0;
+        current = match lookup_tweet(parent_id).await {
+            Ok(parent) => parent,
+            Err(err) => {
+                log::warn!("failed to walk thread: {}", err);
+                partial = true;
+                break;
+            }
+        };
     }
 
-    let mut con = client.unwrap().get_connection().unwrap();
+    thread_ids.reverse();
+    (thread_ids, partial)
+}
+
+/// `shared` is the tweet the thread was asked for, it's the starting point of the walk
+/// when the search finds nothing. Without it (buttons only know the conversation) an old
+/// thread is found only while it's cached. The walk goes up only, so sharing the root of
+/// an old thread never finds the thread.
+///
+/// Threads are cached at positions 1..n, the root being at 0. A partial thread (see
+/// `walk_thread_up`) has its first tweet at 0 instead. Returns the last position.
+async fn fetch_threads(
+    conversation_id: u64,
+    user_id: u64,
+    shared: Option<&TwitDetails>,
+) -> Result<usize, TwideoError> {
+    // check cache if fetch threads before
+    let mut con = storage::connection().await?;
     let redis_key = format!("{}:{}", CONVERSATION_KEY, conversation_id);
+    let refresh_key = format!("{}:fresh", redis_key);
 
-    let mut threads_count: usize = con.hlen(redis_key.clone()).unwrap_or(0);
+    let cached_count: usize = storage::run(con.hlen(&redis_key)).await?;
+    let cached_partial: bool = storage::run(con.hexists(&redis_key, 0)).await?;
+    let fresh: bool = storage::run(con.exists(&refresh_key)).await?;
+    let cached_last = cached_count - usize::from(cached_partial);
 
-    if threads_count > 0 {
+    metrics::cache_lookup("threads", cached_count > 0 && fresh);
+
+    if cached_count > 0 && fresh {
         log::info!("threads exists in cache");
-        return threads_count;
+        return Ok(cached_last);
     }
 
     log::info!("fetch thread");
 
-    let token = TWITTER_BEARER_TOKENS
-        .choose(&mut rand::thread_rng())
-        .unwrap()
-        .to_string();
-
-    let client = reqwest::Client::new();
+    let mut thread_ids = sources::fetch_conversation(conversation_id, user_id).await;
+    let mut partial = false;
 
-    let response = client
-        .get(format!(
-            "{0}?query=conversation_id:{1} from:{2} to:{2}&tweet.fields=author_id,referenced_tweets&max_results=100",
-            &*TWITTER_SEARCH_URL, conversation_id, user_id
-        ))
-        .header("AUTHORIZATION", format!("Bearer {}", token))
-        .send()
-        .await;
+    if thread_ids.is_empty() {
+        if let Some(shared) = shared {
+            log::info!("walk thread");
+            (thread_ids, partial) = walk_thread_up(shared).await;
+        }
+    }
 
-    if response.is_err() {
-        log::info!("fetch thread failed");
-        return 0;
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+
+    // the search forgets threads older than a week, keep what was found before then,
+    // unless it's the whole thread now
+    let completes_cached = cached_partial && !partial && !thread_ids.is_empty();
+
+    if thread_ids.len() <= cached_count && !completes_cached {
+        if cached_count > 0 {
+            pipe.cmd("SET")
+                .arg(&refresh_key)
+                .arg(1)
+                .arg("EX")
+                .arg(REFRESH_KEY_TTL);
+            let _: () = storage::run(pipe.query_async(&mut con)).await?;
+        }
+        return Ok(cached_last);
     }
 
-    let result = response.unwrap();
-    log::info!("Status {}", result.status().as_u16());
+    let first_position = if partial { 0 } else { 1 };
+
+    // save thread_ids to cache
+    pipe.cmd("DEL").arg(&redis_key);
+    for (i, id) in thread_ids.iter().enumerate() {
+        pipe.cmd("HSET")
+            .arg(&redis_key)
+            .arg(i + first_position)
+            .arg(id);
+    }
+    pipe.cmd("EXPIRE").arg(&redis_key).arg(EXPIRE_KEY_TTL);
+    pipe.cmd("SET")
+        .arg(&refresh_key)
+        .arg(1)
+        .arg("EX")
+        .arg(REFRESH_KEY_TTL);
 
-    let response_json = result.json::<ThreadSearchResult>().await.unwrap();
-    let mut search_data = response_json.data.unwrap_or_default();
+    let _: () = storage::run(pipe.query_async(&mut con)).await?;
 
-    let mut thread_ids: Vec<u64> = vec![];
-    let mut last_reference: u64 = 0;
+    Ok(thread_ids.len() + first_position - 1)
+}
 
-    while !search_data.is_empty() {
-        let obj = search_data.pop().unwrap();
-        let current_id = obj.id.parse::<u64>().unwrap();
+pub async fn get_thread(
+    conversation_id: u64,
+    thread_number: u16,
+    user_id: u64,
+) -> Result<Option<u64>, TwideoError> {
+    let mut con = storage::connection().await?;
+    let redis_key = format!("{}:{}", CONVERSATION_KEY, conversation_id);
 
-        if last_reference == 0 {
-            // first thread
-            last_reference = current_id;
-            thread_ids.push(current_id);
+    let tid: Option<u64> = storage::run(con.hget(&redis_key, thread_number)).await?;
 
-            continue;
-        }
+    // only partial threads keep their first tweet at 0, the others start with the root
+    if tid.is_none() && thread_number == 0 {
+        return Ok(Some(conversation_id));
+    }
 
-        let reference = obj
-            .referenced_tweets
-            .into_iter()
-            .find(|x| x.r#type == "replied_to");
+    metrics::cache_lookup("thread", tid.is_some());
 
-        if let Some(reference) = reference {
-            let reference_id = reference.id.parse::<u64>().unwrap();
-            if reference_id == last_reference {
-                last_reference = current_id;
-                thread_ids.push(current_id);
-            } else {
-                break;
-            }
-        } else {
-            break;
-        }
+    if tid.is_some() {
+        return Ok(tid);
     }
 
-    // save thread_ids to cache
-    threads_count = thread_ids.len();
+    let thread_count = fetch_threads(conversation_id, user_id, None).await?;
 
-    if threads_count == 0 {
-        return 0;
+    if thread_count > 0 {
+        return storage::run(con.hget(&redis_key, thread_number)).await;
     }
 
-    let mut pipe = redis::pipe();
+    Ok(None)
+}
 
-    for (i, id) in thread_ids.iter().enumerate() {
-        pipe.cmd("HSET").arg(redis_key.clone()).arg(i + 1).arg(id);
+/// Cached thread tweets ordered by their thread number, without the root unless the
+/// thread is partial and its first tweet is at 0.
+async fn cached_thread(conversation_id: u64) -> Result<Vec<(u16, u64)>, TwideoError> {
+    let mut con = storage::connection().await?;
+    let redis_key = format!("{}:{}", CONVERSATION_KEY, conversation_id);
+
+    let mut threads: Vec<(u16, u64)> = storage::run(con.hgetall(&redis_key)).await?;
+    threads.sort_by_key(|(thread_number, _)| *thread_number);
+
+    Ok(threads)
+}
+
+/// Cached thread with the root at 0, unless the thread is partial.
+async fn thread_with_root(conversation_id: u64) -> Result<Vec<(u16, u64)>, TwideoError> {
+    let mut thread = cached_thread(conversation_id).await?;
+
+    if !thread.iter().any(|(thread_number, _)| *thread_number == 0) {
+        thread.insert(0, (0, conversation_id));
     }
-    pipe.cmd("EXPIRE")
-        .arg(redis_key.clone())
-        .arg(EXPIRE_KEY_TTL);
 
-    let _: () = pipe.query(&mut con).unwrap();
+    Ok(thread)
+}
+
+async fn thread_position(conversation_id: u64, tweet_id: u64) -> Result<Option<u16>, TwideoError> {
+    Ok(thread_with_root(conversation_id)
+        .await?
+        .into_iter()
+        .find(|(_, id)| *id == tweet_id)
+        .map(|(thread_number, _)| thread_number))
+}
+
+/// All tweets of the thread in order, starting with the conversation root (or the first
+/// known tweet of a partial thread).
+pub async fn get_thread_ids(conversation_id: u64, user_id: u64) -> Result<Vec<u64>, TwideoError> {
+    fetch_threads(conversation_id, user_id, None).await?;
 
-    threads_count
+    Ok(thread_with_root(conversation_id)
+        .await?
+        .into_iter()
+        .map(|(_, id)| id)
+        .collect())
 }
 
-pub async fn get_thread(conversation_id: u64, thread_number: u8, user_id: u64) -> Option<u64> {
-    let client = redis::Client::open(&**REDIS_URL);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    if client.is_err() {
-        return None;
+    fn entities(value: serde_json::Value) -> Entities {
+        serde_json::from_value(value).unwrap()
     }
 
-    let mut con = client.unwrap().get_connection().unwrap();
-    let redis_key = format!("{}:{}", CONVERSATION_KEY, conversation_id);
+    #[test]
+    fn entities_are_found_at_their_offsets_ignoring_case() {
+        let text = "@Foo look https://t.co/media @foo";
+        let entities = entities(serde_json::json!({
+            "mentions": [
+                { "start": 0, "end": 4, "username": "foo" },
+                { "start": 29, "end": 33, "username": "foo" },
+            ],
+            "urls": [{
+                "start": 10, "end": 28, "url": "https://t.co/media",
+                "expanded_url": "https://x.com/foo/status/20/photo/1", "media_key": "3_1",
+            }],
+        }));
+
+        assert_eq!(
+            build_caption(text, Some(&entities), true, None),
+            "<a href=\"https://twitter.com/foo\">@Foo</a> look  <a href=\"https://twitter.com/foo\">@foo</a>"
+        );
+    }
 
-    let mut tid: String = con
-        .hget(redis_key.clone(), thread_number)
-        .unwrap_or_else(|_| "".to_string());
+    #[test]
+    fn caption_escapes_html() {
+        assert_eq!(
+            build_caption("if a &lt; b &amp;&amp; c &gt; d { <b> }", None, false, None),
+            "if a &lt; b &amp;&amp; c &gt; d { &lt;b&gt; }"
+        );
+    }
 
-    if !tid.is_empty() {
-        return Some(tid.parse::<u64>().unwrap());
+    #[test]
+    fn caption_expands_links_and_tags() {
+        let text = "read https://t.co/abc #Rust $TSLA &amp; more";
+        let entities = entities(serde_json::json!({
+            "urls": [{
+                "start": 5, "end": 22, "url": "https://t.co/abc",
+                "expanded_url": "https://example.com/?a=1&b=2", "display_url": "example.com/?a=1…",
+            }],
+            "hashtags": [{ "start": 22, "end": 27, "tag": "Rust" }],
+            "cashtags": [{ "start": 28, "end": 33, "tag": "TSLA" }],
+        }));
+
+        assert_eq!(
+            build_caption(text, Some(&entities), false, None),
+            "read <a href=\"https://example.com/?a=1&amp;b=2\">example.com/?a=1…</a> \
+             <a href=\"https://twitter.com/hashtag/Rust\">#Rust</a> \
+             <a href=\"https://twitter.com/search?q=%24TSLA\">$TSLA</a> &amp; more"
+        );
     }
 
-    let thread_count = fetch_threads(conversation_id, user_id).await;
+    #[test]
+    fn caption_hides_quoted_tweet_link() {
+        let text = "so true https://t.co/quote";
+        let entities = entities(serde_json::json!({
+            "urls": [{
+                "start": 8, "end": 26, "url": "https://t.co/quote",
+                "expanded_url": "https://twitter.com/user/status/20",
+            }],
+        }));
+
+        assert_eq!(
+            build_caption(text, Some(&entities), false, Some(20)),
+            "so true"
+        );
+    }
 
-    if thread_count > 0 {
-        tid = con.hget(redis_key, thread_number).unwrap();
-        return Some(tid.parse::<u64>().unwrap());
+    #[test]
+    fn detected_entities_hide_the_last_link_of_media_tweets() {
+        assert_eq!(
+            build_caption(
+                "hi @user #tag https://t.co/link https://t.co/media",
+                None,
+                true,
+                None
+            ),
+            "hi <a href=\"https://twitter.com/user\">@user</a> \
+             <a href=\"https://twitter.com/hashtag/tag\">#tag</a> \
+             <a href=\"https://t.co/link\">https://t.co/link</a>"
+        );
+    }
+
+    #[test]
+    fn split_html_keeps_short_text() {
+        assert_eq!(
+            split_html("<b>short</b> text", 20),
+            vec!["<b>short</b> text"]
+        );
     }
 
-    None
+    #[test]
+    fn split_html_cuts_inside_blockquote() {
+        let quote = format!(
+            "<blockquote expandable>{}</blockquote>",
+            "word ".repeat(500).trim_end()
+        );
+        let html = format!("intro {}", quote);
+
+        let chunks = split_html(&html, CAPTION_LIMIT);
+
+        assert!(chunks.len() > 1);
+        assert!(visible_len(&chunks[0]) <= CAPTION_LIMIT);
+        for chunk in &chunks {
+            assert!(visible_len(chunk) <= MESSAGE_LIMIT);
+            assert_eq!(
+                chunk.matches("<blockquote").count(),
+                chunk.matches("</blockquote>").count()
+            );
+        }
+        assert!(chunks[1].starts_with("<blockquote expandable>"));
+    }
+
+    #[test]
+    fn split_html_doesnt_cut_links() {
+        let link = format!(
+            "<a href=\"https://example.com\">{}</a>",
+            "long link ".repeat(20)
+        );
+        let html = format!("{} {}", "word ".repeat(100), link);
+
+        for chunk in split_html(&html, 600) {
+            assert_eq!(chunk.matches("<a ").count(), chunk.matches("</a>").count());
+        }
+    }
 }
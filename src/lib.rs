@@ -1,8 +1,9 @@
 pub mod serde_schemes {
-    use serde::Deserialize;
+    use serde::{Deserialize, Serialize};
 
-    #[derive(Deserialize, Debug, Clone)]
+    #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct Variant {
+        #[serde(alias = "bitrate")]
         pub bit_rate: Option<i32>,
         pub content_type: String,
         pub url: String,
@@ -17,14 +18,20 @@ pub mod serde_schemes {
 
     #[derive(Deserialize, Debug)]
     pub struct Media {
+        pub media_key: Option<String>,
         pub r#type: String,
         pub preview_image_url: Option<String>,
         pub variants: Option<Vec<Variant>>,
         pub url: Option<String>,
+        pub width: Option<u32>,
+        pub height: Option<u32>,
+        pub duration_ms: Option<u32>,
+        pub alt_text: Option<String>,
     }
 
     #[derive(Deserialize, Debug)]
     pub struct TwitterUser {
+        pub id: Option<String>,
         pub name: String,
         pub username: String,
     }
@@ -33,19 +40,110 @@ pub mod serde_schemes {
     pub struct MultimediaIncludes {
         pub media: Option<Vec<Media>>,
         pub users: Vec<TwitterUser>,
+        pub tweets: Option<Vec<MultimediaData>>,
+        pub polls: Option<Vec<Poll>>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct PollOption {
+        pub label: String,
+        pub votes: u64,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct Poll {
+        pub id: String,
+        pub options: Vec<PollOption>,
+        pub voting_status: Option<String>,
+        pub end_datetime: Option<String>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct Attachments {
+        #[serde(default)]
+        pub media_keys: Vec<String>,
+        #[serde(default)]
+        pub poll_ids: Vec<String>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct UrlEntity {
+        pub start: usize,
+        pub end: usize,
+        pub url: String,
+        pub expanded_url: Option<String>,
+        pub display_url: Option<String>,
+        /// Set for the links to the attached media.
+        pub media_key: Option<String>,
+        pub unwound_url: Option<String>,
+        /// Link card details, when twitter shows a card for the link.
+        pub title: Option<String>,
+        pub description: Option<String>,
+        pub images: Option<Vec<UrlImage>>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct UrlImage {
+        pub url: String,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct MentionEntity {
+        pub start: usize,
+        pub end: usize,
+        pub username: String,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct TagEntity {
+        pub start: usize,
+        pub end: usize,
+        pub tag: String,
+    }
+
+    #[derive(Deserialize, Debug, Default)]
+    pub struct Entities {
+        #[serde(default)]
+        pub urls: Vec<UrlEntity>,
+        #[serde(default)]
+        pub mentions: Vec<MentionEntity>,
+        #[serde(default)]
+        pub hashtags: Vec<TagEntity>,
+        #[serde(default)]
+        pub cashtags: Vec<TagEntity>,
+    }
+
+    /// Full text of the tweets longer than 280 characters, `text` holds only the beginning.
+    #[derive(Deserialize, Debug)]
+    pub struct NoteTweet {
+        pub text: String,
+        pub entities: Option<Entities>,
     }
 
     #[derive(Deserialize, Debug)]
     pub struct MultimediaData {
+        pub id: Option<String>,
         pub text: Option<String>,
+        pub note_tweet: Option<NoteTweet>,
+        pub entities: Option<Entities>,
         pub conversation_id: Option<String>,
         pub author_id: Option<String>,
+        pub attachments: Option<Attachments>,
+        #[serde(default)]
+        pub referenced_tweets: Vec<ReferencedTweets>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct ApiError {
+        pub r#type: Option<String>,
+        pub detail: Option<String>,
     }
 
     #[derive(Deserialize, Debug)]
     pub struct MultimediaBody {
         pub includes: Option<MultimediaIncludes>,
-        pub data: MultimediaData,
+        pub data: Option<MultimediaData>,
+        pub errors: Option<Vec<ApiError>>,
     }
 
     #[derive(Deserialize, Debug)]
@@ -57,11 +155,78 @@ pub mod serde_schemes {
     #[derive(Deserialize, Debug)]
     pub struct ThreadSearchData {
         pub id: String,
+        #[serde(default)]
         pub referenced_tweets: Vec<ReferencedTweets>,
     }
 
+    #[derive(Deserialize, Debug)]
+    pub struct SearchMeta {
+        pub next_token: Option<String>,
+    }
+
     #[derive(Deserialize, Debug)]
     pub struct ThreadSearchResult {
         pub data: Option<Vec<ThreadSearchData>>,
+        pub meta: Option<SearchMeta>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct VideoInfo {
+        pub variants: Vec<Variant>,
+        pub duration_millis: Option<u32>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct OriginalInfo {
+        pub width: u32,
+        pub height: u32,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct StatusMedia {
+        pub r#type: String,
+        pub media_url_https: String,
+        pub video_info: Option<VideoInfo>,
+        pub original_info: Option<OriginalInfo>,
+        pub ext_alt_text: Option<String>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct StatusExtendedEntities {
+        pub media: Vec<StatusMedia>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct StatusBody {
+        pub full_text: Option<String>,
+        pub text: Option<String>,
+        pub user: User,
+        pub extended_entities: Option<StatusExtendedEntities>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct StatusError {
+        pub code: u32,
+        pub message: Option<String>,
+    }
+
+    #[derive(Deserialize, Debug, Default)]
+    pub struct StatusErrors {
+        #[serde(default)]
+        pub errors: Vec<StatusError>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct SyndicationKind {
+        #[serde(rename = "__typename")]
+        pub typename: Option<String>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct SyndicationBody {
+        pub text: String,
+        pub user: User,
+        #[serde(rename = "mediaDetails")]
+        pub media_details: Option<Vec<StatusMedia>>,
     }
 }
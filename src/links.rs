@@ -0,0 +1,249 @@
+use reqwest::{redirect::Policy, Url};
+use std::{env, time::Duration};
+use teloxide::types::{Message, MessageEntityKind};
+
+const MAX_REDIRECTS: u8 = 5;
+
+lazy_static::lazy_static! {
+    static ref TWITTER_HOSTS: Vec<&'static str> = vec![
+        "twitter.com",
+        "mobile.twitter.com",
+        "x.com",
+        "mobile.x.com",
+        "fxtwitter.com",
+        "vxtwitter.com",
+        "fixupx.com",
+        "fixvx.com",
+    ];
+    static ref SHORT_LINK_HOST: &'static str = "t.co";
+    static ref SHORT_LINK_RESOLVER: String =
+        env::var("SHORT_LINK_RESOLVER").unwrap_or_else(|_| "https://t.co".to_string());
+}
+
+pub enum TwitterID {
+    Id(u64),
+    None,
+}
+
+/// Parses anything that looks like a link, adding a scheme when the user omitted it.
+fn parse_link(link: &str) -> Option<Url> {
+    let link = link.trim_matches(|c: char| matches!(c, '<' | '>' | '(' | ')' | '"' | '\''));
+
+    if link.starts_with("http://") || link.starts_with("https://") {
+        Url::parse(link).ok()
+    } else {
+        Url::parse(&format!("https://{}", link)).ok()
+    }
+}
+
+fn host_of(url: &Url) -> Option<String> {
+    url.host_str()
+        .map(|host| host.trim_start_matches("www.").to_lowercase())
+}
+
+fn is_twitter_host(host: &str) -> bool {
+    TWITTER_HOSTS.contains(&host) || host.starts_with("nitter.")
+}
+
+fn is_short_link(url: &Url) -> bool {
+    host_of(url).as_deref() == Some(*SHORT_LINK_HOST)
+}
+
+/// Checks whether the link points to something the bot knows how to resolve.
+pub fn is_supported_link(link: &str) -> bool {
+    match parse_link(link) {
+        Some(url) => is_short_link(&url) || host_of(&url).is_some_and(|h| is_twitter_host(&h)),
+        None => false,
+    }
+}
+
+/// Extracts the status id from a tweet link, e.g. `https://x.com/<user>/status/<id>`
+/// or `https://twitter.com/i/web/status/<id>`.
+pub fn get_twitter_id(link: &str) -> TwitterID {
+    let url = match parse_link(link) {
+        Some(url) => url,
+        None => return TwitterID::None,
+    };
+
+    match host_of(&url) {
+        Some(host) if is_twitter_host(&host) => (),
+        _ => return TwitterID::None,
+    }
+
+    let segments: Vec<&str> = match url.path_segments() {
+        Some(segments) => segments.filter(|s| !s.is_empty()).collect(),
+        None => return TwitterID::None,
+    };
+
+    let possible_id = segments
+        .iter()
+        .position(|segment| *segment == "status" || *segment == "statuses")
+        .and_then(|i| segments.get(i + 1))
+        .and_then(|id| id.parse::<u64>().ok())
+        .unwrap_or(0);
+
+    if possible_id > 0 {
+        TwitterID::Id(possible_id)
+    } else {
+        TwitterID::None
+    }
+}
+
+/// Same as `get_twitter_id`, but follows `t.co` short links to the tweet they point to.
+pub async fn resolve_twitter_id(link: &str) -> TwitterID {
+    let mut url = match parse_link(link) {
+        Some(url) => url,
+        None => return TwitterID::None,
+    };
+
+    let client = match reqwest::Client::builder()
+        .redirect(Policy::none())
+        .timeout(Duration::from_secs(5))
+        .build()
+    {
+        Ok(client) => client,
+        Err(_) => return TwitterID::None,
+    };
+
+    for _ in 0..MAX_REDIRECTS {
+        if !is_short_link(&url) {
+            return get_twitter_id(url.as_str());
+        }
+
+        log::info!("Resolve short link {}", url);
+
+        let resolver_url = format!(
+            "{}{}",
+            SHORT_LINK_RESOLVER.trim_end_matches('/'),
+            url.path()
+        );
+
+        let response = match client.get(resolver_url).send().await {
+            Ok(response) => response,
+            Err(_) => return TwitterID::None,
+        };
+
+        let location = response
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(parse_link);
+
+        match location {
+            Some(location) => url = location,
+            None => return TwitterID::None,
+        }
+    }
+
+    TwitterID::None
+}
+
+/// Collects candidate tweet links from the message text, its caption and their entities
+/// (including links hidden behind text).
+pub fn extract_links(message: &Message) -> Vec<String> {
+    let mut links: Vec<String> = Vec::new();
+
+    let entities = message
+        .parse_entities()
+        .or_else(|| message.parse_caption_entities())
+        .unwrap_or_default();
+
+    for entity in entities {
+        let link = match entity.kind() {
+            MessageEntityKind::TextLink { url } => url.to_string(),
+            MessageEntityKind::Url => entity.text().to_string(),
+            _ => continue,
+        };
+
+        if is_supported_link(&link) && !links.contains(&link) {
+            links.push(link);
+        }
+    }
+
+    if let Some(text) = message.text().or_else(|| message.caption()) {
+        for part in text.split_ascii_whitespace() {
+            if is_supported_link(part) && !links.iter().any(|link| link == part) {
+                links.push(part.to_string());
+            }
+        }
+    }
+
+    links
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id_of(link: &str) -> Option<u64> {
+        match get_twitter_id(link) {
+            TwitterID::Id(id) => Some(id),
+            TwitterID::None => None,
+        }
+    }
+
+    #[test]
+    fn status_ids_of_all_hosts() {
+        for (link, id) in [
+            ("https://twitter.com/user/status/20", Some(20)),
+            ("https://www.twitter.com/user/status/20", Some(20)),
+            ("https://mobile.twitter.com/user/status/20", Some(20)),
+            (
+                "https://x.com/user/status/1234567890123456789",
+                Some(1234567890123456789),
+            ),
+            ("https://mobile.x.com/user/status/20?s=20", Some(20)),
+            ("https://fxtwitter.com/user/status/20", Some(20)),
+            ("https://vxtwitter.com/user/status/20/", Some(20)),
+            ("https://fixupx.com/user/status/20", Some(20)),
+            ("https://fixvx.com/user/status/20", Some(20)),
+            ("https://nitter.net/user/status/20#m", Some(20)),
+            ("https://X.COM/user/status/20", Some(20)),
+            ("https://twitter.com/i/web/status/20", Some(20)),
+            ("https://twitter.com/user/statuses/20", Some(20)),
+            ("http://twitter.com/user/status/20", Some(20)),
+            ("x.com/user/status/20", Some(20)),
+            ("<https://x.com/user/status/20>", Some(20)),
+            ("https://x.com/user", None),
+            ("https://x.com/user/status/", None),
+            ("https://x.com/user/status/abc", None),
+            ("https://example.com/user/status/20", None),
+            ("https://t.co/abc", None),
+        ] {
+            assert_eq!(id_of(link), id, "id of {}", link);
+        }
+    }
+
+    #[test]
+    fn short_strings_are_no_links() {
+        for link in [
+            "",
+            "x",
+            "http://",
+            "https://",
+            "twitter",
+            "x.com",
+            "/status/20",
+        ] {
+            assert_eq!(id_of(link), None, "id of {:?}", link);
+        }
+    }
+
+    #[test]
+    fn supported_links() {
+        for (link, supported) in [
+            ("https://twitter.com/user/status/20", true),
+            ("http://x.com/user/status/20", true),
+            ("mobile.twitter.com/user", true),
+            ("https://fxtwitter.com/user/status/20", true),
+            ("https://nitter.poast.org/user/status/20", true),
+            ("https://t.co/abc", true),
+            ("https://example.com/twitter", false),
+            ("twitter", false),
+            ("", false),
+            ("https://", false),
+        ] {
+            assert_eq!(is_supported_link(link), supported, "support of {}", link);
+        }
+    }
+}
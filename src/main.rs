@@ -1,29 +1,80 @@
 extern crate dotenvy;
 extern crate twitter_video_dl;
 
+mod callback;
+mod download;
+mod errors;
+mod file_cache;
 mod helpers;
+mod links;
+mod metrics;
+mod server;
+mod settings;
+mod sources;
+mod storage;
+mod telegraph;
+mod thread_messages;
+mod tweet_cache;
 
+use callback::CallbackAction;
 use chrono::Local;
 use dotenvy::dotenv;
-use helpers::{get_thread, get_twitter_data, get_twitter_id, TwitDetails, TwitterID};
+use errors::TwideoError;
+use helpers::{
+    compose_caption, get_thread, get_thread_ids, get_twitter_data, split_html, TwitDetails,
+    VideoMetadata, CAPTION_LIMIT, MESSAGE_LIMIT,
+};
+use links::{extract_links, is_supported_link, resolve_twitter_id, TwitterID};
 use reqwest::Url;
-use std::error::Error;
+use settings::{ChatSettings, Setting, VideoQuality};
 use std::io::Write;
 use teloxide::{
     payloads::SendMessageSetters,
     prelude::*,
+    requests::HasPayload,
     types::{
-        InlineKeyboardButton, InlineKeyboardMarkup, InputFile, InputMedia, InputMediaPhoto,
-        InputMediaVideo, ParseMode, Recipient,
+        Chat, InlineKeyboardButton, InlineKeyboardButtonKind, InlineKeyboardMarkup,
+        InlineQueryResult, InlineQueryResultArticle, InlineQueryResultPhoto,
+        InlineQueryResultVideo, InputFile, InputMedia, InputMediaAnimation, InputMediaPhoto,
+        InputMediaVideo, InputMessageContent, InputMessageContentText, MessageId, ParseMode,
+        Recipient,
     },
+    utils::{command::BotCommands, html},
+    ApiError, RequestError,
 };
 use twitter_video_dl::serde_schemes::Variant;
 
+#[derive(Clone, PartialEq)]
+enum MediaKind {
+    Photo,
+    Video,
+    Animation,
+}
+
+#[derive(Clone)]
+struct MediaItem {
+    kind: MediaKind,
+    file: InputFile,
+    thumb: String,
+    /// Telegram takes the thumbnail only along with an uploaded file.
+    thumbnail: Option<InputFile>,
+    metadata: VideoMetadata,
+    /// Video variants to fall back to when telegram refuses the file, the best one first.
+    variants: Vec<Variant>,
+}
+
+/// Media sent as one telegram message: an album of photos and videos or a single item.
+/// Animations can't be a part of an album, so they always go alone.
+struct MediaPart {
+    items: Vec<MediaItem>,
+}
+
 struct MediaWithExtra {
-    media: Vec<InputMedia>,
-    extra_urls: Vec<Variant>,
+    tweet_id: u64,
+    parts: Vec<MediaPart>,
     caption: String,
-    allowed: bool,
+    /// Rest of the caption that didn't fit into the media caption limit.
+    follow_up: Vec<String>,
     keyboard: Option<Vec<Vec<InlineKeyboardButton>>>,
 }
 
@@ -35,305 +86,1240 @@ struct TelegramTextMessage {
 enum TelegramMessage {
     Media(MediaWithExtra),
     Text(TelegramTextMessage),
-    Unauthorized(i32),
-    TooManyRequest(i32),
+    Error(TwideoError),
     None,
 }
 
-const FULL_ALBUM: u8 = 1;
-const THREAD: u8 = 2;
+const ALBUM_LIMIT: usize = 10;
+// unrolling into messages floods the chat, so longer threads are cut
+const MAX_UNROLL_MESSAGES: usize = 30;
 
-fn message_response_cb(twitter_data: &TwitDetails) -> TelegramMessage {
-    let mut caption_is_set = false;
-    let mut media_group = Vec::new();
-    let mut allowed = false;
+#[derive(BotCommands, Clone)]
+#[command(
+    rename_rule = "lowercase",
+    description = "These commands are supported:"
+)]
+enum Command {
+    #[command(description = "configure how tweets are shown in this chat.")]
+    Settings,
+}
 
-    let keyboard =
-        if twitter_data.thread_count > 0 && twitter_data.next <= twitter_data.thread_count as u8 {
-            Some(vec![vec![InlineKeyboardButton::callback(
-                "Next tweet from thread".to_string(),
-                format!(
-                    "{}_{}_{}_{}",
-                    THREAD, twitter_data.conversation_id, twitter_data.user_id, twitter_data.next
-                ),
-            )]])
-        } else {
-            None
+/// Navigation through the thread. Positions are the numbers of the thread tweets, the
+/// conversation root being 0, and `next` of the shown tweet is one past its position.
+fn thread_keyboard(twitter_data: &TwitDetails) -> Vec<Vec<InlineKeyboardButton>> {
+    let last = twitter_data.thread_count;
+    let button = |text: &str, position: usize| {
+        InlineKeyboardButton::callback(
+            text.to_string(),
+            CallbackAction::Thread {
+                conversation_id: twitter_data.conversation_id,
+                user_id: twitter_data.user_id,
+                position: position.try_into().unwrap_or(u16::MAX),
+            }
+            .encode(),
+        )
+    };
+
+    let unroll = vec![InlineKeyboardButton::callback(
+        "🧵 Unroll thread".to_string(),
+        CallbackAction::Unroll {
+            conversation_id: twitter_data.conversation_id,
+            user_id: twitter_data.user_id,
+        }
+        .encode(),
+    )];
+
+    // a reply on a side branch, all we can do is to offer the thread itself
+    if twitter_data.next == 0 {
+        return vec![vec![button("⏮ Thread start", 0)], unroll];
+    }
+
+    let current = twitter_data.next as usize - 1;
+    let mut navigation = Vec::new();
+
+    if current > 0 {
+        navigation.push(button("⏮", 0));
+        navigation.push(button("◀️", current - 1));
+    }
+
+    navigation.push(InlineKeyboardButton::callback(
+        format!("{}/{}", current + 1, last + 1),
+        CallbackAction::ThreadPosition.encode(),
+    ));
+
+    if current < last {
+        navigation.push(button("▶️", current + 1));
+        navigation.push(button("⏭", last));
+    }
+
+    vec![navigation, unroll]
+}
+
+fn without_unroll(markup: &InlineKeyboardMarkup) -> Vec<Vec<InlineKeyboardButton>> {
+    let is_unroll = |button: &InlineKeyboardButton| match &button.kind {
+        InlineKeyboardButtonKind::CallbackData(data) => matches!(
+            CallbackAction::decode(data),
+            Some(CallbackAction::Unroll { .. })
+        ),
+        _ => false,
+    };
+
+    markup
+        .inline_keyboard
+        .iter()
+        .filter(|row| !row.iter().any(is_unroll))
+        .cloned()
+        .collect()
+}
+
+fn settings_keyboard(settings: &ChatSettings) -> Vec<Vec<InlineKeyboardButton>> {
+    settings.keyboard(|setting| {
+        CallbackAction::Settings {
+            setting: setting as u8,
+        }
+        .encode()
+    })
+}
+
+fn message_response_cb(twitter_data: &TwitDetails, settings: &ChatSettings) -> TelegramMessage {
+    let caption = compose_caption(twitter_data, settings);
+    let mut caption_chunks = split_html(&caption, CAPTION_LIMIT).into_iter();
+    let media_caption = caption_chunks.next().unwrap_or_default();
+
+    let keyboard = if settings.thread_button && twitter_data.thread_count > 0 {
+        Some(thread_keyboard(twitter_data))
+    } else {
+        None
+    };
+
+    let mut parts: Vec<MediaPart> = Vec::new();
+    let mut album = MediaPart { items: Vec::new() };
+
+    for media in twitter_data.album() {
+        let kind = match media.r#type.as_str() {
+            "photo" => MediaKind::Photo,
+            "video" => MediaKind::Video,
+            "animated_gif" => MediaKind::Animation,
+            _ => continue,
         };
 
-    for media in &twitter_data.twitter_media {
-        let input_file = InputFile::url(Url::parse(&media.url).unwrap());
+        // uploaded file is the best quality one, so it's reused only when the chat wants it
+        let file_id = media
+            .file_id
+            .as_ref()
+            .filter(|_| kind == MediaKind::Photo || settings.video_quality == VideoQuality::Best);
 
-        if media.r#type == "photo" {
-            let mut tl_media = InputMediaPhoto::new(input_file);
-            if !caption_is_set {
-                tl_media = tl_media
-                    .caption(&twitter_data.caption)
-                    .parse_mode(ParseMode::Html);
-                caption_is_set = true;
-            }
-            media_group.push(InputMedia::Photo(tl_media));
-        } else if media.r#type == "video" || media.r#type == "animated_gif" {
-            allowed = true;
-            let mut tl_media = InputMediaVideo::new(input_file);
-            if !caption_is_set {
-                tl_media = tl_media
-                    .caption(&twitter_data.caption)
-                    .parse_mode(ParseMode::Html);
-                caption_is_set = true;
+        let file = match file_id {
+            Some(file_id) => InputFile::file_id(file_id),
+            None => match Url::parse(media.url_for(settings.video_quality)) {
+                Ok(url) => InputFile::url(url),
+                Err(_) => continue,
+            },
+        };
+
+        if kind == MediaKind::Animation || album.items.len() == ALBUM_LIMIT {
+            if !album.items.is_empty() {
+                parts.push(album);
             }
-            media_group.push(InputMedia::Video(tl_media));
+            album = MediaPart { items: Vec::new() };
+        }
+
+        let item = MediaItem {
+            kind,
+            file,
+            thumb: media.thumb.to_string(),
+            thumbnail: None,
+            metadata: media.metadata.clone(),
+            variants: media.variants_for(settings.video_quality).to_vec(),
+        };
+
+        if item.kind == MediaKind::Animation {
+            parts.push(MediaPart { items: vec![item] });
+        } else {
+            album.items.push(item);
         }
     }
-    if !caption_is_set {
+
+    if !album.items.is_empty() {
+        parts.push(album);
+    }
+
+    // tweets without media show the link card image instead
+    let card_image = twitter_data
+        .card
+        .as_ref()
+        .and_then(|card| card.image.as_deref())
+        .and_then(|image| Url::parse(image).ok());
+
+    if let (true, Some(card_image)) = (parts.is_empty(), card_image) {
+        parts.push(MediaPart {
+            items: vec![MediaItem {
+                kind: MediaKind::Photo,
+                file: InputFile::url(card_image),
+                thumb: String::new(),
+                thumbnail: None,
+                metadata: VideoMetadata::default(),
+                variants: Vec::new(),
+            }],
+        });
+    }
+
+    if parts.is_empty() {
         return TelegramMessage::Text(TelegramTextMessage {
-            text: twitter_data.caption.to_string(),
+            text: caption,
             keyboard,
         });
     }
 
     TelegramMessage::Media(MediaWithExtra {
-        media: media_group,
-        extra_urls: twitter_data.extra_urls.to_vec(),
-        caption: twitter_data.caption.to_string(),
-        allowed,
+        tweet_id: twitter_data.id,
+        parts,
+        caption: media_caption,
+        follow_up: caption_chunks.collect(),
         keyboard,
     })
 }
 
+fn inline_response_cb(
+    twitter_data: &TwitDetails,
+    settings: &ChatSettings,
+) -> Vec<InlineQueryResult> {
+    let mut results = Vec::new();
+    let caption = compose_caption(twitter_data, settings);
+    // inline results can't be followed by more messages, so the long text is cut
+    let media_caption = split_html(&caption, CAPTION_LIMIT).swap_remove(0);
+    let text = split_html(&caption, MESSAGE_LIMIT).swap_remove(0);
+    // a result holds a single media, the rest of the album can be sent to the user privately
+    let album_len = twitter_data.album().count();
+    let full_album = InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback(
+        format!("🖼 Full album ({})", album_len),
+        CallbackAction::FullAlbum {
+            tweet_id: twitter_data.id,
+        }
+        .encode(),
+    )]]);
+
+    for (i, media) in twitter_data.album().enumerate() {
+        let result_id = format!("{}_{}", twitter_data.id, i);
+        let (media_url, thumb_url) = match (
+            Url::parse(media.url_for(settings.video_quality)),
+            Url::parse(&media.thumb),
+        ) {
+            (Ok(media_url), Ok(thumb_url)) => (media_url, thumb_url),
+            _ => continue,
+        };
+
+        if media.r#type == "photo" {
+            let mut result = InlineQueryResultPhoto::new(result_id, media_url, thumb_url)
+                .title(&twitter_data.name)
+                .caption(&media_caption)
+                .parse_mode(ParseMode::Html);
+
+            if album_len > 1 {
+                result = result.reply_markup(full_album.clone());
+            }
+
+            results.push(InlineQueryResult::Photo(result));
+        } else if media.r#type == "video" || media.r#type == "animated_gif" {
+            let mut result = InlineQueryResultVideo::new(
+                result_id,
+                media_url,
+                "video/mp4".parse::<mime::Mime>().unwrap(),
+                thumb_url,
+                &twitter_data.name,
+            )
+            .caption(&media_caption)
+            .parse_mode(ParseMode::Html);
+
+            if album_len > 1 {
+                result = result.reply_markup(full_album.clone());
+            }
+
+            results.push(InlineQueryResult::Video(result));
+        }
+    }
+
+    if results.is_empty() {
+        results.push(InlineQueryResult::Article(InlineQueryResultArticle::new(
+            twitter_data.id.to_string(),
+            &twitter_data.name,
+            InputMessageContent::Text(
+                InputMessageContentText::new(&text)
+                    .parse_mode(ParseMode::Html)
+                    .disable_web_page_preview(true),
+            ),
+        )));
+    }
+
+    results
+}
+
 async fn convert_to_telegram<F>(url: &str, callback: F) -> TelegramMessage
 where
     F: Fn(&TwitDetails) -> TelegramMessage,
 {
-    if let TwitterID::Id(id) = get_twitter_id(url) {
-        let data = get_twitter_data(id).await;
+    if let TwitterID::Id(id) = resolve_twitter_id(url).await {
+        return match get_twitter_data(id).await {
+            Ok(twitter_data) => callback(&twitter_data),
+            Err(err) => TelegramMessage::Error(err),
+        };
+    }
+
+    TelegramMessage::None
+}
+
+async fn convert_to_telegram_by_id<F>(id: u64, callback: F) -> TelegramMessage
+where
+    F: Fn(&TwitDetails) -> TelegramMessage,
+{
+    match get_twitter_data(id).await {
+        Ok(twitter_data) => callback(&twitter_data),
+        Err(err) => TelegramMessage::Error(err),
+    }
+}
+
+/// `offset` is the position of the first sent message in the whole tweet album.
+async fn cache_file_ids(tweet_id: u64, offset: usize, sent_messages: &[Message]) {
+    for (i, message) in sent_messages.iter().enumerate() {
+        let file_id = message
+            .video()
+            .map(|video| &video.file.id)
+            .or_else(|| message.animation().map(|animation| &animation.file.id))
+            .or_else(|| {
+                message
+                    .photo()
+                    .and_then(|sizes| sizes.last())
+                    .map(|size| &size.file.id)
+            });
+
+        if let Some(file_id) = file_id {
+            file_cache::save_file_id(tweet_id, offset + i, file_id).await;
+        }
+    }
+}
+
+/// Telegram fetches urls only up to 20 MB, but bots can upload up to 50 MB, so the videos
+/// of the items are downloaded and uploaded by ourselves. `None` when any of them can't be.
+async fn send_downloaded_videos<Contact>(
+    items: &[MediaItem],
+    caption: &str,
+    message_to_reply: Option<&Message>,
+    bot: &Bot,
+    chat_id: Contact,
+    settings: &ChatSettings,
+) -> Option<Vec<Message>>
+where
+    Contact: Into<Recipient> + Copy,
+{
+    let mut paths = Vec::new();
+    let mut uploads = Vec::new();
+
+    for item in items {
+        let mut item = item.clone();
 
-        if data.is_ok() {
-            if let Some(twitter_data) = data.unwrap() {
-                return callback(&twitter_data);
+        if !item.variants.is_empty() {
+            let variant = match download::pick_uploadable_variant(&item.variants).await {
+                Some(variant) => variant,
+                None => break,
+            };
+
+            let path = match download::download_variant(&variant).await {
+                Ok(path) => path,
+                Err(err) => {
+                    log::warn!("failed to download {}: {}", variant.url, err);
+                    break;
+                }
+            };
+
+            item.file = InputFile::file(&path);
+            item.thumbnail = download::fetch_thumbnail(&item.thumb).await;
+            paths.push(path);
+        }
+
+        uploads.push(item);
+    }
+
+    let sent_messages = if uploads.len() == items.len() {
+        send_media_items(uploads, caption, message_to_reply, bot, chat_id, settings)
+            .await
+            .map_err(|err| log::warn!("failed to upload media: {}", err))
+            .ok()
+    } else {
+        None
+    };
+
+    for path in paths {
+        _ = tokio::fs::remove_file(&path).await;
+    }
+
+    sent_messages
+}
+
+/// Items with every video swapped for its variant `step` places below the one tried
+/// first, or the lowest one when the video has fewer variants.
+fn lower_bitrate_items(items: &[MediaItem], step: usize) -> Vec<MediaItem> {
+    items
+        .iter()
+        .map(|item| {
+            let mut item = item.clone();
+            let url = item
+                .variants
+                .get(step)
+                .or(item.variants.last())
+                .and_then(|variant| Url::parse(&variant.url).ok());
+
+            if let Some(url) = url {
+                item.file = InputFile::url(url);
             }
 
-            return TelegramMessage::TooManyRequest(429);
+            item
+        })
+        .collect()
+}
+
+/// Whether telegram failed to take the media from its url, which sending it some other way
+/// may help with. Flood control, blocked bots or missing rights won't change that way.
+fn is_fetch_error(err: &RequestError) -> bool {
+    match err {
+        RequestError::Api(
+            ApiError::WrongFileIdOrUrl
+            | ApiError::FailedToGetUrlContent
+            | ApiError::ImageProcessFailed,
+        ) => true,
+        RequestError::Api(ApiError::Unknown(message)) => {
+            message.contains("WEBPAGE_") || message.contains("wrong type of the web page content")
         }
-        return TelegramMessage::Unauthorized(401);
+        _ => false,
     }
+}
 
-    TelegramMessage::None
+fn input_media(item: MediaItem, caption: &str) -> InputMedia {
+    match item.kind {
+        MediaKind::Photo => InputMedia::Photo(
+            InputMediaPhoto::new(item.file)
+                .caption(caption)
+                .parse_mode(ParseMode::Html),
+        ),
+        MediaKind::Video => {
+            let mut video = InputMediaVideo::new(item.file)
+                .caption(caption)
+                .parse_mode(ParseMode::Html)
+                .supports_streaming(true);
+
+            // telegram wants u16 here, unlike in send_video
+            video.width = item.metadata.width.and_then(|width| width.try_into().ok());
+            video.height = item
+                .metadata
+                .height
+                .and_then(|height| height.try_into().ok());
+            video.duration = item
+                .metadata
+                .duration
+                .and_then(|duration| duration.try_into().ok());
+            video.thumb = item.thumbnail;
+
+            InputMedia::Video(video)
+        }
+        MediaKind::Animation => {
+            let mut animation = InputMediaAnimation::new(item.file)
+                .caption(caption)
+                .parse_mode(ParseMode::Html);
+            animation.thumb = item.thumbnail;
+
+            InputMedia::Animation(animation)
+        }
+    }
 }
 
-async fn convert_to_telegram_by_id<F>(id: u64, next: u8, callback: F) -> TelegramMessage
+async fn send_media_items<Contact>(
+    mut items: Vec<MediaItem>,
+    caption: &str,
+    message_to_reply: Option<&Message>,
+    bot: &Bot,
+    chat_id: Contact,
+    settings: &ChatSettings,
+) -> Result<Vec<Message>, RequestError>
 where
-    F: Fn(&TwitDetails) -> TelegramMessage,
+    Contact: Into<Recipient> + Copy,
+{
+    let reply_to = message_to_reply.map(|message| message.id);
+
+    if items.len() > 1 {
+        let media = items.into_iter().enumerate().map(|(i, item)| {
+            let caption = if i == 0 { caption } else { "" };
+            input_media(item, caption)
+        });
+
+        let mut telegram_message = bot
+            .send_media_group(chat_id, media)
+            .disable_notification(settings.silent);
+
+        if let Some(reply_to) = reply_to {
+            telegram_message = telegram_message.reply_to_message_id(reply_to)
+        }
+
+        return telegram_message.await;
+    }
+
+    let item = match items.pop() {
+        Some(item) => item,
+        None => return Ok(vec![]),
+    };
+
+    let sent_message = match item.kind {
+        MediaKind::Photo => {
+            let mut telegram_message = bot
+                .send_photo(chat_id, item.file)
+                .caption(caption)
+                .parse_mode(ParseMode::Html)
+                .disable_notification(settings.silent);
+
+            if let Some(reply_to) = reply_to {
+                telegram_message = telegram_message.reply_to_message_id(reply_to)
+            }
+
+            telegram_message.await?
+        }
+        MediaKind::Video => {
+            let mut telegram_message = bot
+                .send_video(chat_id, item.file)
+                .caption(caption)
+                .parse_mode(ParseMode::Html)
+                .supports_streaming(true)
+                .disable_notification(settings.silent);
+
+            let payload = telegram_message.payload_mut();
+            payload.width = item.metadata.width;
+            payload.height = item.metadata.height;
+            payload.duration = item.metadata.duration;
+            payload.thumb = item.thumbnail;
+
+            if let Some(reply_to) = reply_to {
+                telegram_message = telegram_message.reply_to_message_id(reply_to)
+            }
+
+            telegram_message.await?
+        }
+        MediaKind::Animation => {
+            let mut telegram_message = bot
+                .send_animation(chat_id, item.file)
+                .caption(caption)
+                .parse_mode(ParseMode::Html)
+                .disable_notification(settings.silent);
+
+            let payload = telegram_message.payload_mut();
+            payload.width = item.metadata.width;
+            payload.height = item.metadata.height;
+            payload.duration = item.metadata.duration;
+            payload.thumb = item.thumbnail;
+
+            if let Some(reply_to) = reply_to {
+                telegram_message = telegram_message.reply_to_message_id(reply_to)
+            }
+
+            telegram_message.await?
+        }
+    };
+
+    Ok(vec![sent_message])
+}
+
+/// Messages a media part ended up as.
+struct SentPart {
+    messages: Vec<Message>,
+    /// Whether the files are worth reusing for the next shares of the tweet, the lower
+    /// bitrate and link fallbacks aren't.
+    cacheable: bool,
+}
+
+/// Sends the part of the tweet media, falling back to other ways to show it when telegram
+/// can't fetch the files.
+async fn send_media_part<Contact>(
+    part: MediaPart,
+    caption: Option<&str>,
+    message_to_reply: Option<&Message>,
+    bot: &Bot,
+    chat_id: Contact,
+    settings: &ChatSettings,
+) -> SentPart
+where
+    Contact: Into<Recipient> + Copy,
 {
-    let data = get_twitter_data(id).await;
+    let caption = caption.unwrap_or_default();
+    let items = part.items;
+
+    let err = loop {
+        match send_media_items(
+            items.clone(),
+            caption,
+            message_to_reply,
+            bot,
+            chat_id,
+            settings,
+        )
+        .await
+        {
+            Ok(messages) => {
+                metrics::SEND_FALLBACK.with_label_values(&["direct"]).inc();
+                return SentPart {
+                    messages,
+                    cacheable: true,
+                };
+            }
+            // flood control only asks to wait, the media itself is fine
+            Err(RequestError::RetryAfter(wait)) => tokio::time::sleep(wait).await,
+            Err(err) => break err,
+        }
+    };
+
+    log::warn!("failed to send media: {}", err);
+
+    let fallback = |messages: Vec<Message>| SentPart {
+        messages,
+        cacheable: false,
+    };
+
+    // only videos have something to fall back to, and only when telegram couldn't get them
+    if !is_fetch_error(&err) || items.iter().all(|item| item.variants.is_empty()) {
+        return fallback(vec![]);
+    }
+
+    // the uploaded files are as good as telegram can take, so they are worth caching
+
+    if let Some(messages) =
+        send_downloaded_videos(&items, caption, message_to_reply, bot, chat_id, settings).await
+    {
+        metrics::SEND_FALLBACK.with_label_values(&["upload"]).inc();
+        return SentPart {
+            messages,
+            cacheable: true,
+        };
+    }
+
+    // too big even to upload, let's try sending lower sizes
 
-    if data.is_ok() {
-        if let Some(mut twitter_data) = data.unwrap() {
-            twitter_data.next = next;
-            return callback(&twitter_data);
+    let steps = items
+        .iter()
+        .map(|item| item.variants.len())
+        .max()
+        .unwrap_or(0);
+
+    for step in 1..steps {
+        if let Ok(messages) = send_media_items(
+            lower_bitrate_items(&items, step),
+            caption,
+            message_to_reply,
+            bot,
+            chat_id,
+            settings,
+        )
+        .await
+        {
+            metrics::SEND_FALLBACK
+                .with_label_values(&["lower_bitrate"])
+                .inc();
+            return fallback(messages);
         }
+    }
+
+    // if still failure let's send media as a link and hope
+    // telegram will preview it
 
-        return TelegramMessage::TooManyRequest(429);
+    metrics::SEND_FALLBACK
+        .with_label_values(&["link_only"])
+        .inc();
+
+    let mut text = "🤖 failed to embed media so use link this time:".to_string();
+    for variant in items.iter().filter_map(|item| item.variants.first()) {
+        text.push('\n');
+        text.push_str(&html::escape(&variant.url));
+    }
+    text.push_str("\n\n");
+    text.push_str(caption);
+
+    let mut telegram_message = bot
+        .send_message(chat_id, text)
+        .disable_notification(settings.silent)
+        .parse_mode(ParseMode::Html);
+
+    if let Some(message_to_reply) = message_to_reply {
+        telegram_message = telegram_message.reply_to_message_id(message_to_reply.id)
     }
 
-    TelegramMessage::Unauthorized(401)
+    fallback(telegram_message.await.into_iter().collect())
 }
 
+async fn send_follow_up<Contact>(
+    texts: Vec<String>,
+    bot: &Bot,
+    chat_id: Contact,
+    settings: &ChatSettings,
+) -> Result<Vec<MessageId>, TwideoError>
+where
+    Contact: Into<Recipient> + Copy,
+{
+    let mut message_ids = Vec::new();
+
+    for text in texts {
+        let message = bot
+            .send_message(chat_id, text)
+            .disable_notification(settings.silent)
+            .parse_mode(ParseMode::Html)
+            .disable_web_page_preview(true)
+            .await?;
+
+        message_ids.push(message.id);
+    }
+
+    Ok(message_ids)
+}
+
+/// Tells whether the tweet was sent, errors shown in its place don't count.
 async fn send_telegram_message<Contact>(
     message_to_send: TelegramMessage,
     message_to_reply: Option<&Message>,
     bot: &Bot,
     chat_id: Contact,
-) -> Result<(), Box<dyn Error + Send + Sync>>
+    settings: &ChatSettings,
+) -> Result<bool, TwideoError>
 where
     Contact: Into<Recipient> + Copy,
 {
     match message_to_send {
         TelegramMessage::Text(response) => {
-            let mut telegram_message = bot
-                .send_message(chat_id, response.text)
-                .disable_notification(true)
-                .parse_mode(ParseMode::Html)
-                .disable_web_page_preview(true);
+            let chunks = split_html(&response.text, MESSAGE_LIMIT);
+            let last = chunks.len() - 1;
 
-            if let Some(message_to_reply) = message_to_reply {
-                telegram_message = telegram_message.reply_to_message_id(message_to_reply.id)
+            // the text is too long for one message, so the rest follows the first part
+            // and the keyboard goes with the last one
+            for (i, chunk) in chunks.into_iter().enumerate() {
+                let mut telegram_message = bot
+                    .send_message(chat_id, chunk)
+                    .disable_notification(settings.silent)
+                    .parse_mode(ParseMode::Html)
+                    .disable_web_page_preview(true);
+
+                if let (0, Some(message_to_reply)) = (i, message_to_reply) {
+                    telegram_message = telegram_message.reply_to_message_id(message_to_reply.id)
+                }
+
+                if let (true, Some(keyboard)) = (i == last, &response.keyboard) {
+                    telegram_message =
+                        telegram_message.reply_markup(InlineKeyboardMarkup::new(keyboard.clone()));
+                }
+
+                telegram_message.await?;
             }
 
-            if let Some(keyboard) = response.keyboard {
-                telegram_message
+            Ok(true)
+        }
+        TelegramMessage::Media(media_with_extra) => {
+            let mut caption = Some(media_with_extra.caption.as_str());
+            let mut offset = 0;
+            let lone_part =
+                media_with_extra.parts.len() == 1 && media_with_extra.follow_up.is_empty();
+            let mut lone_message = None;
+            let mut message_ids = Vec::new();
+
+            for part in media_with_extra.parts {
+                let len = part.items.len();
+                let sent_part = send_media_part(
+                    part,
+                    caption.take(),
+                    message_to_reply,
+                    bot,
+                    chat_id,
+                    settings,
+                )
+                .await;
+
+                if sent_part.cacheable && settings.video_quality == VideoQuality::Best {
+                    cache_file_ids(media_with_extra.tweet_id, offset, &sent_part.messages).await;
+                }
+
+                offset += len;
+                message_ids.extend(sent_part.messages.iter().map(|message| message.id));
+
+                if let (true, [message]) = (lone_part, sent_part.messages.as_slice()) {
+                    lone_message = Some((message.chat.id, message.id));
+                }
+            }
+
+            let sent = !message_ids.is_empty();
+
+            message_ids
+                .extend(send_follow_up(media_with_extra.follow_up, bot, chat_id, settings).await?);
+
+            if let Some(keyboard) = media_with_extra.keyboard {
+                // albums can't have a keyboard, but a lone media message can carry it itself,
+                // so the thread navigation is able to edit it in place
+                if let Some((message_chat_id, message_id)) = lone_message {
+                    if bot
+                        .edit_message_reply_markup(message_chat_id, message_id)
+                        .reply_markup(InlineKeyboardMarkup::new(keyboard.clone()))
+                        .await
+                        .is_ok()
+                    {
+                        return Ok(sent);
+                    }
+                }
+
+                let keyboard_message = bot
+                    .send_message(chat_id, "tap button to see next thread")
+                    .disable_notification(settings.silent)
+                    .parse_mode(ParseMode::Html)
+                    .disable_web_page_preview(true)
                     .reply_markup(InlineKeyboardMarkup::new(keyboard))
                     .await?;
-            } else {
-                telegram_message.await?;
+
+                thread_messages::save(keyboard_message.chat.id, keyboard_message.id, &message_ids)
+                    .await;
             }
+
+            Ok(sent)
         }
-        TelegramMessage::Media(media_with_extra) => {
+        TelegramMessage::Error(err) => {
+            log::warn!("{}", err);
+
             let mut telegram_message = bot
-                .send_media_group(chat_id, media_with_extra.media)
-                .disable_notification(true);
+                .send_message(chat_id, err.user_message())
+                .disable_notification(settings.silent)
+                .disable_web_page_preview(true);
 
             if let Some(message_to_reply) = message_to_reply {
                 telegram_message = telegram_message.reply_to_message_id(message_to_reply.id)
             }
 
-            let response = telegram_message.await;
+            telegram_message.await?;
 
-            if response.is_ok() {
-                if let Some(keyboard) = media_with_extra.keyboard {
-                    bot.send_message(chat_id, "tap button to see next thread")
-                        .disable_notification(true)
-                        .parse_mode(ParseMode::Html)
-                        .disable_web_page_preview(true)
-                        .reply_markup(InlineKeyboardMarkup::new(keyboard))
-                        .await?;
-                }
-            } else if media_with_extra.allowed {
-                // seems like too high quality video for telegram to download let's try sending
-                // lower sizes
-                let mut success = false;
-
-                for variant in &media_with_extra.extra_urls {
-                    let mut telegram_message = bot
-                        .send_media_group(
-                            chat_id,
-                            [InputMedia::Video(
-                                InputMediaVideo::new(InputFile::url(
-                                    Url::parse(variant.url.as_str()).unwrap(),
-                                ))
-                                .caption(&media_with_extra.caption)
-                                .parse_mode(ParseMode::Html),
-                            )],
-                        )
-                        .disable_notification(true);
-
-                    if let Some(message_to_reply) = message_to_reply {
-                        telegram_message = telegram_message.reply_to_message_id(message_to_reply.id)
-                    }
+            Ok(false)
+        }
+        TelegramMessage::None => Ok(false),
+    }
+}
 
-                    let response = telegram_message.await;
+/// Replaces the content of `message_to_edit` with the tweet, telling whether telegram
+/// allowed to do that.
+async fn edit_in_place(
+    message_to_send: &TelegramMessage,
+    message_to_edit: &Message,
+    bot: &Bot,
+) -> bool {
+    let (chat_id, message_id) = (message_to_edit.chat.id, message_to_edit.id);
+    let has_media = message_to_edit.photo().is_some()
+        || message_to_edit.video().is_some()
+        || message_to_edit.animation().is_some();
 
-                    match response {
-                        Err(_) => success = false,
-                        Ok(_) => success = true,
-                    }
-                }
+    let edited = match message_to_send {
+        TelegramMessage::Text(response) if message_to_edit.text().is_some() => {
+            let mut chunks = split_html(&response.text, MESSAGE_LIMIT);
+            if chunks.len() != 1 {
+                return false;
+            }
 
-                // if still failure let's send media as a link and hope
-                // telegram will preview it
+            let mut telegram_message = bot
+                .edit_message_text(chat_id, message_id, chunks.remove(0))
+                .parse_mode(ParseMode::Html)
+                .disable_web_page_preview(true);
 
-                if !success {
-                    let mut text: String =
-                        "🤖 failed to embed media so use link this time: ".to_owned();
-                    text.push_str(&media_with_extra.extra_urls.first().unwrap().url);
-                    text.push_str("\n\n");
-                    text.push_str(&media_with_extra.caption);
+            if let Some(keyboard) = &response.keyboard {
+                telegram_message =
+                    telegram_message.reply_markup(InlineKeyboardMarkup::new(keyboard.clone()));
+            }
 
-                    let mut telegram_message = bot
-                        .send_message(chat_id, text)
-                        .disable_notification(true)
-                        .parse_mode(ParseMode::Html);
+            telegram_message.await
+        }
+        TelegramMessage::Media(media_with_extra)
+            if has_media && media_with_extra.follow_up.is_empty() =>
+        {
+            let item = match media_with_extra.parts.as_slice() {
+                [MediaPart { items, .. }] if items.len() == 1 => items[0].clone(),
+                _ => return false,
+            };
 
-                    if let Some(message_to_reply) = message_to_reply {
-                        telegram_message = telegram_message.reply_to_message_id(message_to_reply.id)
-                    }
+            let mut telegram_message = bot.edit_message_media(
+                chat_id,
+                message_id,
+                input_media(item, &media_with_extra.caption),
+            );
 
-                    _ = telegram_message.await;
-                }
+            if let Some(keyboard) = &media_with_extra.keyboard {
+                telegram_message =
+                    telegram_message.reply_markup(InlineKeyboardMarkup::new(keyboard.clone()));
             }
+
+            telegram_message.await
         }
-        TelegramMessage::TooManyRequest(_code) => {
-            bot.send_message(
-                chat_id,
-                "🧑‍💻👨‍💻⚠️ Server is busy! Please try a little later.",
-            )
-            .disable_web_page_preview(true)
-            .await?;
+        _ => return false,
+    };
+
+    match edited {
+        Ok(_) => true,
+        Err(err) => {
+            log::warn!("failed to edit thread message: {}", err);
+            false
         }
-        TelegramMessage::Unauthorized(_code) => {
-            // bot.send_message(chat_id, "☠️ Bot is stopped to work due to Twitter's new API plan(<a href='https://twitter.com/TwitterDev/status/1641222786894135296'>click to see announcement</a>). But don't despair. 👀 I'm looking for a way to come back. Be patient 💪🏻")
-            // .parse_mode(ParseMode::Html)
-            // .disable_web_page_preview(true)
-            // .await?;
+    }
+}
+
+async fn message_handler(message: Message, bot: Bot) -> Result<(), TwideoError> {
+    let chat = &message.chat;
+    let links = extract_links(&message);
+
+    // groups send every message here, most of them without any links
+    if links.is_empty() {
+        return Ok(());
+    }
+
+    let settings = settings::get(chat.id).await;
+    let mut converted = false;
+
+    // there will be nothing to reply to if the original message is deleted
+    let message_to_reply = if settings.delete_original {
+        None
+    } else {
+        Some(&message)
+    };
+
+    for link in links {
+        metrics::LINKS_PROCESSED.inc();
+        let timer = metrics::LINK_LATENCY.start_timer();
+
+        let content_to_send = convert_to_telegram(&link, |twitter_data| {
+            message_response_cb(twitter_data, &settings)
+        })
+        .await;
+
+        // an error isn't a reason to delete the original message, so it replies to it
+        let reply_to = match content_to_send {
+            TelegramMessage::Error(_) => Some(&message),
+            _ => message_to_reply,
+        };
+
+        converted |=
+            send_telegram_message(content_to_send, reply_to, &bot, chat.id, &settings).await?;
+
+        timer.observe_duration();
+    }
+
+    if converted && settings.delete_original {
+        if let Err(err) = bot.delete_message(chat.id, message.id).await {
+            log::warn!("failed to delete original message: {}", err);
         }
-        _ => (),
     }
 
     Ok(())
 }
 
-async fn message_handler(message: Message, bot: Bot) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let chat = &message.chat;
+/// Only admins are allowed to change how the bot behaves in groups.
+async fn can_change_settings(bot: &Bot, chat: &Chat, user_id: UserId) -> bool {
+    if chat.is_private() {
+        return true;
+    }
 
-    let futures = if let Some(text) = message.text() {
-        if !text.contains("twitter") {
-            return Ok(());
-        };
+    match bot.get_chat_member(chat.id, user_id).await {
+        Ok(member) => member.is_privileged(),
+        Err(err) => {
+            log::warn!("failed to get chat member: {}", err);
+            false
+        }
+    }
+}
 
-        text.split_ascii_whitespace()
-            .filter(|part| part.contains("twitter"))
-            .map(|part| convert_to_telegram(part, message_response_cb))
-            .collect()
+async fn command_handler(message: Message, bot: Bot, command: Command) -> Result<(), TwideoError> {
+    match command {
+        Command::Settings => {
+            let user_id = match message.from() {
+                Some(user) => user.id,
+                None => return Ok(()),
+            };
+
+            if !can_change_settings(&bot, &message.chat, user_id).await {
+                bot.send_message(message.chat.id, "Only chat admins can change settings")
+                    .reply_to_message_id(message.id)
+                    .await?;
+                return Ok(());
+            }
+
+            let settings = settings::get(message.chat.id).await;
+
+            bot.send_message(message.chat.id, "⚙️ Settings for this chat")
+                .reply_markup(InlineKeyboardMarkup::new(settings_keyboard(&settings)))
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn inline_query_handler(q: InlineQuery, bot: Bot) -> Result<(), TwideoError> {
+    let link = q
+        .query
+        .split_ascii_whitespace()
+        .find(|part| is_supported_link(part));
+
+    let results = if let Some(link) = link {
+        metrics::LINKS_PROCESSED.inc();
+
+        if let TwitterID::Id(id) = resolve_twitter_id(link).await {
+            match get_twitter_data(id).await {
+                Ok(twitter_data) => {
+                    let settings = settings::get(ChatId::from(q.from.id)).await;
+                    inline_response_cb(&twitter_data, &settings)
+                }
+                Err(err) => {
+                    log::warn!("{}", err);
+                    vec![]
+                }
+            }
+        } else {
+            vec![]
+        }
     } else {
-        vec![]
+        return Ok(());
     };
 
-    for future in futures {
-        let content_to_send = future.await;
-        send_telegram_message(content_to_send, Some(&message), &bot, chat.id).await?;
+    bot.answer_inline_query(q.id, results).await?;
+
+    Ok(())
+}
+
+/// Publishes the whole thread as a Telegraph article, or sends its tweets one by one when
+/// `THREAD_UNROLL` is set to `messages` or the article can't be published.
+async fn unroll_thread(
+    thread_ids: &[u64],
+    pressed_message: &Message,
+    bot: &Bot,
+    settings: &ChatSettings,
+) -> Result<(), TwideoError> {
+    let chat_id = pressed_message.chat.id;
+
+    if telegraph::is_enabled() {
+        let mut tweets = Vec::new();
+
+        for id in thread_ids {
+            match get_twitter_data(*id).await {
+                Ok(twitter_data) => tweets.push(twitter_data),
+                Err(err) => log::warn!("failed to unroll tweet {}: {}", id, err),
+            }
+        }
+
+        if !tweets.is_empty() {
+            let article = telegraph::thread_article(&tweets);
+
+            match telegraph::publish(&article).await {
+                Ok(url) => {
+                    bot.send_message(chat_id, format!("🧵 {}", html::link(&url, &article.title)))
+                        .parse_mode(ParseMode::Html)
+                        .reply_to_message_id(pressed_message.id)
+                        .disable_notification(settings.silent)
+                        .await?;
+                    return Ok(());
+                }
+                Err(err) => log::warn!("failed to publish thread: {}", err),
+            }
+        }
+    }
+
+    // every tweet of the unrolled thread is already here, so no need for the thread button
+    let settings = ChatSettings {
+        thread_button: false,
+        ..settings.clone()
+    };
+
+    for id in thread_ids.iter().take(MAX_UNROLL_MESSAGES) {
+        // the tweets go one after another, so telegram flood control is bound to kick in
+        loop {
+            let response = convert_to_telegram_by_id(*id, |twitter_data| {
+                message_response_cb(twitter_data, &settings)
+            })
+            .await;
+
+            match send_telegram_message(response, None, bot, chat_id, &settings).await {
+                Err(TwideoError::TelegramRetryAfter(wait)) => tokio::time::sleep(wait).await,
+                result => {
+                    result?;
+                    break;
+                }
+            }
+        }
+    }
+
+    if let Some(next_id) = thread_ids.get(MAX_UNROLL_MESSAGES) {
+        bot.send_message(
+            chat_id,
+            format!(
+                "🧵 {} more tweets of the thread continue {}",
+                thread_ids.len() - MAX_UNROLL_MESSAGES,
+                html::link(
+                    &format!("https://twitter.com/i/web/status/{}", next_id),
+                    "on Twitter"
+                )
+            ),
+        )
+        .parse_mode(ParseMode::Html)
+        .disable_notification(settings.silent)
+        .await?;
     }
 
     Ok(())
 }
 
-async fn callback_queries_handler(
-    q: CallbackQuery,
-    bot: Bot,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let query = q.data.unwrap();
-    let query_parts = query.split('_').collect::<Vec<&str>>();
-    let query_type = query_parts[0].parse::<u8>().unwrap();
+async fn callback_queries_handler(q: CallbackQuery, bot: Bot) -> Result<(), TwideoError> {
+    let action = match q.data.as_deref().and_then(CallbackAction::decode) {
+        Some(action) => action,
+        None => {
+            bot.answer_callback_query(q.id)
+                .text("This button is outdated, send the link again")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    match action {
+        CallbackAction::FullAlbum { tweet_id } => {
+            _ = bot.answer_callback_query(q.id).await;
 
-    match query_type {
-        FULL_ALBUM => {
-            // query template: <query-type>_<tweet-id>
-            let tid = query_parts[1].parse::<u64>().unwrap();
-            let response = convert_to_telegram_by_id(tid, 1, message_response_cb).await;
-            send_telegram_message(response, None, &bot, q.from.id).await?;
+            let settings = settings::get(ChatId::from(q.from.id)).await;
+            let response = convert_to_telegram_by_id(tweet_id, |twitter_data| {
+                message_response_cb(twitter_data, &settings)
+            })
+            .await;
+            send_telegram_message(response, None, &bot, q.from.id, &settings).await?;
         }
-        THREAD => {
+        CallbackAction::Thread {
+            conversation_id,
+            user_id,
+            position,
+        } => {
             if let Some(pressed_message) = q.message {
+                _ = bot.answer_callback_query(q.id).await;
+
+                let chat_id = pressed_message.chat.id;
+                let settings = settings::get(chat_id).await;
+
+                let tweet_id = get_thread(conversation_id, position, user_id).await;
+
+                let response = match tweet_id {
+                    Ok(Some(tweet_id)) => {
+                        convert_to_telegram_by_id(tweet_id, |twitter_data| {
+                            message_response_cb(twitter_data, &settings)
+                        })
+                        .await
+                    }
+                    Ok(None) => TelegramMessage::Error(TwideoError::NotFound),
+                    Err(err) => TelegramMessage::Error(err),
+                };
+
+                // keep the thread message around when there's nothing to replace it with
+                if let TelegramMessage::Error(_) = response {
+                    send_telegram_message(
+                        response,
+                        Some(&pressed_message),
+                        &bot,
+                        chat_id,
+                        &settings,
+                    )
+                    .await?;
+                    return Ok(());
+                }
+
+                // the pressed message may be just the keyboard under the tweet album
+                let tweet_messages = thread_messages::take(chat_id, pressed_message.id).await;
+
+                if tweet_messages.is_empty()
+                    && edit_in_place(&response, &pressed_message, &bot).await
+                {
+                    return Ok(());
+                }
+
+                // telegram can't turn a text message into a media one and back, so post
+                // the tweet anew in place of the pressed message
+                send_telegram_message(
+                    response,
+                    pressed_message.reply_to_message(),
+                    &bot,
+                    chat_id,
+                    &settings,
+                )
+                .await?;
+
+                for message_id in tweet_messages
+                    .into_iter()
+                    .chain(std::iter::once(pressed_message.id))
+                {
+                    if let Err(err) = bot.delete_message(chat_id, message_id).await {
+                        log::warn!("failed to delete previous thread message: {}", err);
+                    }
+                }
+            };
+        }
+        CallbackAction::Unroll {
+            conversation_id,
+            user_id,
+        } => {
+            let pressed_message = match q.message {
+                Some(pressed_message) => pressed_message,
+                None => return Ok(()),
+            };
+
+            // the thread can't be unrolled twice, but it's still navigable
+            if let Some(markup) = pressed_message.reply_markup() {
                 _ = bot
                     .edit_message_reply_markup(pressed_message.chat.id, pressed_message.id)
+                    .reply_markup(InlineKeyboardMarkup::new(without_unroll(markup)))
                     .await;
+            }
+            _ = bot
+                .answer_callback_query(q.id)
+                .text("Unrolling the thread…")
+                .await;
 
-                // query template: <query-type>_<conversation-id>_<user-id>_<thread-number>
-                let conversation_id = query_parts[1].parse::<u64>().unwrap();
-                let user_id = query_parts[2].parse::<u64>().unwrap();
-                let thread_number = query_parts[3].parse::<u8>().unwrap();
-
-                let tid = get_thread(conversation_id, thread_number, user_id).await;
+            let settings = settings::get(pressed_message.chat.id).await;
 
-                if let Some(tweet_id) = tid {
-                    let response =
-                        convert_to_telegram_by_id(tweet_id, thread_number + 1, message_response_cb)
-                            .await;
+            match get_thread_ids(conversation_id, user_id).await {
+                Ok(thread_ids) => {
+                    unroll_thread(&thread_ids, &pressed_message, &bot, &settings).await?
+                }
+                Err(err) => {
                     send_telegram_message(
-                        response,
+                        TelegramMessage::Error(err),
                         Some(&pressed_message),
                         &bot,
                         pressed_message.chat.id,
+                        &settings,
                     )
                     .await?;
-                } else {
-                    bot.send_message(pressed_message.chat.id, "Thread not found 🤷‍♂️")
-                        .reply_to_message_id(pressed_message.id)
-                        .disable_notification(true)
-                        .await?;
                 }
+            }
+        }
+        CallbackAction::ThreadPosition => {
+            bot.answer_callback_query(q.id).await?;
+        }
+        CallbackAction::Settings { setting } => {
+            let (pressed_message, setting) = match (q.message, Setting::from_index(setting)) {
+                (Some(pressed_message), Some(setting)) => (pressed_message, setting),
+                _ => return Ok(()),
             };
+
+            if !can_change_settings(&bot, &pressed_message.chat, q.from.id).await {
+                bot.answer_callback_query(q.id)
+                    .text("Only chat admins can change settings")
+                    .show_alert(true)
+                    .await?;
+                return Ok(());
+            }
+
+            let chat_id = pressed_message.chat.id;
+            let mut settings = settings::get(chat_id).await;
+            settings.toggle(setting);
+            settings::save(chat_id, &settings).await?;
+
+            bot.edit_message_reply_markup(chat_id, pressed_message.id)
+                .reply_markup(InlineKeyboardMarkup::new(settings_keyboard(&settings)))
+                .await?;
+            bot.answer_callback_query(q.id).await?;
         }
-        _ => {}
     }
 
     Ok(())
@@ -359,15 +1345,51 @@ async fn main() {
 
     log::info!("Starting twideo");
 
+    if storage::is_configured() {
+        if let Err(err) = storage::connection().await {
+            log::warn!(
+                "Redis is unavailable, threads are disabled until it's back: {}",
+                err
+            );
+        }
+    }
+
     let bot = Bot::from_env();
 
+    if let Err(err) = bot.set_my_commands(Command::bot_commands()).await {
+        log::warn!("failed to set bot commands: {}", err);
+    }
+
     let handler = dptree::entry()
-        .branch(Update::filter_message().endpoint(message_handler))
+        .branch(
+            Update::filter_message()
+                .branch(
+                    dptree::entry()
+                        .filter_command::<Command>()
+                        .endpoint(command_handler),
+                )
+                .branch(dptree::endpoint(message_handler)),
+        )
+        .branch(Update::filter_inline_query().endpoint(inline_query_handler))
         .branch(Update::filter_callback_query().endpoint(callback_queries_handler));
 
-    Dispatcher::builder(bot, handler)
+    let mut dispatcher = Dispatcher::builder(bot.clone(), handler)
         .enable_ctrlc_handler()
-        .build()
-        .dispatch()
-        .await;
+        .build();
+
+    if let Some(url) = server::webhook_url() {
+        let listener = server::webhook_listener(bot, url)
+            .await
+            .expect("Couldn't setup webhook");
+
+        dispatcher
+            .dispatch_with_listener(
+                listener,
+                LoggingErrorHandler::with_custom_text("An error from the update listener"),
+            )
+            .await;
+    } else {
+        server::serve_metrics();
+        dispatcher.dispatch().await;
+    }
 }
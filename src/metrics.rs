@@ -0,0 +1,58 @@
+use prometheus::{
+    register_histogram, register_int_counter, register_int_counter_vec, Encoder, Histogram,
+    IntCounter, IntCounterVec, TextEncoder,
+};
+
+lazy_static::lazy_static! {
+    pub static ref LINKS_PROCESSED: IntCounter = register_int_counter!(
+        "twideo_links_processed_total",
+        "Tweet links the bot tried to convert"
+    )
+    .unwrap();
+    pub static ref UPSTREAM_RESPONSES: IntCounterVec = register_int_counter_vec!(
+        "twideo_upstream_responses_total",
+        "Responses of the tweet backends by status code",
+        &["source", "status"]
+    )
+    .unwrap();
+    pub static ref TOKEN_RATE_LIMITED: IntCounterVec = register_int_counter_vec!(
+        "twideo_token_rate_limited_total",
+        "429 responses per bearer token (last 4 characters)",
+        &["token"]
+    )
+    .unwrap();
+    pub static ref CACHE_LOOKUPS: IntCounterVec = register_int_counter_vec!(
+        "twideo_cache_lookups_total",
+        "Cache lookups by cache and result",
+        &["cache", "result"]
+    )
+    .unwrap();
+    pub static ref SEND_FALLBACK: IntCounterVec = register_int_counter_vec!(
+        "twideo_send_fallback_total",
+        "How far sending media had to fall back (direct, upload, lower_bitrate, link_only)",
+        &["depth"]
+    )
+    .unwrap();
+    pub static ref LINK_LATENCY: Histogram = register_histogram!(
+        "twideo_link_latency_seconds",
+        "Time from receiving a link to sending the reply"
+    )
+    .unwrap();
+}
+
+pub fn cache_lookup(cache: &str, hit: bool) {
+    CACHE_LOOKUPS
+        .with_label_values(&[cache, if hit { "hit" } else { "miss" }])
+        .inc();
+}
+
+/// Everything registered above in the prometheus text format.
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+
+    if let Err(err) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        log::warn!("failed to encode metrics: {}", err);
+    }
+
+    String::from_utf8(buffer).unwrap_or_default()
+}
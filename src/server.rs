@@ -0,0 +1,110 @@
+use crate::metrics;
+use axum::{routing::get, Router};
+use reqwest::Url;
+use std::{convert::Infallible, env, net::SocketAddr};
+use teloxide::{
+    prelude::*,
+    stop::StopToken,
+    update_listeners::{webhooks, UpdateListener},
+};
+
+lazy_static::lazy_static! {
+    static ref WEBHOOK_URL: String = env::var("WEBHOOK_URL").unwrap_or_else(|_| "".to_string());
+    static ref WEBHOOK_PORT: u16 = env::var("WEBHOOK_PORT")
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(8080);
+    static ref WEBHOOK_SECRET: String = env::var("WEBHOOK_SECRET").unwrap_or_else(|_| "".to_string());
+    static ref METRICS_PORT: Option<u16> = env::var("METRICS_PORT")
+        .ok()
+        .and_then(|port| port.parse().ok());
+}
+
+/// Webhook mode is on when the public url telegram should post updates to is configured.
+pub fn webhook_url() -> Option<Url> {
+    if WEBHOOK_URL.is_empty() {
+        return None;
+    }
+
+    match Url::parse(&WEBHOOK_URL) {
+        Ok(url) => Some(url),
+        Err(err) => {
+            log::error!("WEBHOOK_URL is invalid, falling back to polling: {}", err);
+            None
+        }
+    }
+}
+
+async fn health() -> &'static str {
+    "ok"
+}
+
+async fn metrics() -> String {
+    metrics::render()
+}
+
+/// Routes served next to the webhook: health checks and prometheus metrics.
+fn service_router() -> Router {
+    Router::new()
+        .route("/health", get(health))
+        .route("/metrics", get(metrics))
+}
+
+/// In polling mode there is no webhook server, so `/health` and `/metrics` get their own
+/// one on `METRICS_PORT` (when it's set).
+pub fn serve_metrics() {
+    let port = match *METRICS_PORT {
+        Some(port) => port,
+        None => return,
+    };
+
+    let address = SocketAddr::from(([0, 0, 0, 0], port));
+
+    log::info!("Serving metrics on {}", address);
+
+    tokio::spawn(async move {
+        let result = axum::Server::bind(&address)
+            .serve(service_router().into_make_service())
+            .await;
+
+        if let Err(err) = result {
+            log::error!("Metrics server error: {}", err);
+        }
+    });
+}
+
+/// Registers the webhook and starts the http server receiving updates. Telegram has to send
+/// the `WEBHOOK_SECRET` (generated when not set) back in the secret token header, other
+/// requests are rejected.
+pub async fn webhook_listener(
+    bot: Bot,
+    url: Url,
+) -> Result<impl UpdateListener<Err = Infallible>, teloxide::RequestError> {
+    let address = SocketAddr::from(([0, 0, 0, 0], *WEBHOOK_PORT));
+    let mut options = webhooks::Options::new(address, url);
+
+    if !WEBHOOK_SECRET.is_empty() {
+        options = options.secret_token(WEBHOOK_SECRET.to_string());
+    }
+
+    let (mut listener, stop_flag, router) = webhooks::axum_to_router(bot, options).await?;
+    let stop_token: StopToken = listener.stop_token();
+
+    let app = router.merge(service_router());
+
+    log::info!("Listening for webhook updates on {}", address);
+
+    tokio::spawn(async move {
+        let result = axum::Server::bind(&address)
+            .serve(app.into_make_service())
+            .with_graceful_shutdown(stop_flag)
+            .await;
+
+        if let Err(err) = result {
+            log::error!("Webhook server error: {}", err);
+            stop_token.stop();
+        }
+    });
+
+    Ok(listener)
+}
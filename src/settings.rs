@@ -0,0 +1,181 @@
+use crate::{errors::TwideoError, storage};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Mutex};
+use teloxide::types::{ChatId, InlineKeyboardButton};
+
+const SETTINGS_KEY: &str = "settings";
+
+lazy_static::lazy_static! {
+    static ref MEMORY_SETTINGS: Mutex<HashMap<ChatId, ChatSettings>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoQuality {
+    Best,
+    Medium,
+    Low,
+}
+
+impl VideoQuality {
+    fn next(self) -> Self {
+        match self {
+            VideoQuality::Best => VideoQuality::Medium,
+            VideoQuality::Medium => VideoQuality::Low,
+            VideoQuality::Low => VideoQuality::Best,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            VideoQuality::Best => "best",
+            VideoQuality::Medium => "medium",
+            VideoQuality::Low => "low",
+        }
+    }
+}
+
+/// How the bot renders tweets in a particular chat.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ChatSettings {
+    pub caption: bool,
+    pub author_link: bool,
+    pub silent: bool,
+    pub video_quality: VideoQuality,
+    pub thread_button: bool,
+    pub delete_original: bool,
+    pub alt_text: bool,
+}
+
+impl Default for ChatSettings {
+    fn default() -> Self {
+        ChatSettings {
+            caption: true,
+            author_link: true,
+            silent: true,
+            video_quality: VideoQuality::Best,
+            thread_button: true,
+            delete_original: false,
+            alt_text: true,
+        }
+    }
+}
+
+/// Settings that can be changed from the `/settings` menu. The discriminant goes
+/// to the callback data, so don't reorder them.
+#[derive(Debug, Clone, Copy)]
+pub enum Setting {
+    Caption = 0,
+    AuthorLink = 1,
+    Silent = 2,
+    VideoQuality = 3,
+    ThreadButton = 4,
+    DeleteOriginal = 5,
+    AltText = 6,
+}
+
+impl Setting {
+    const ALL: [Setting; 7] = [
+        Setting::Caption,
+        Setting::AuthorLink,
+        Setting::Silent,
+        Setting::VideoQuality,
+        Setting::ThreadButton,
+        Setting::DeleteOriginal,
+        Setting::AltText,
+    ];
+
+    pub fn from_index(index: u8) -> Option<Setting> {
+        Setting::ALL.get(index as usize).copied()
+    }
+}
+
+impl ChatSettings {
+    pub fn toggle(&mut self, setting: Setting) {
+        match setting {
+            Setting::Caption => self.caption = !self.caption,
+            Setting::AuthorLink => self.author_link = !self.author_link,
+            Setting::Silent => self.silent = !self.silent,
+            Setting::VideoQuality => self.video_quality = self.video_quality.next(),
+            Setting::ThreadButton => self.thread_button = !self.thread_button,
+            Setting::DeleteOriginal => self.delete_original = !self.delete_original,
+            Setting::AltText => self.alt_text = !self.alt_text,
+        }
+    }
+
+    fn button_text(&self, setting: Setting) -> String {
+        let (enabled, title) = match setting {
+            Setting::Caption => (self.caption, "Tweet text"),
+            Setting::AuthorLink => (self.author_link, "Author link"),
+            Setting::Silent => (self.silent, "Silent messages"),
+            Setting::VideoQuality => {
+                return format!("🎞 Video quality: {}", self.video_quality.label())
+            }
+            Setting::ThreadButton => (self.thread_button, "Thread button"),
+            Setting::DeleteOriginal => (self.delete_original, "Delete original message"),
+            Setting::AltText => (self.alt_text, "Image descriptions"),
+        };
+
+        format!("{} {}", if enabled { "✅" } else { "❌" }, title)
+    }
+
+    /// Menu with a button per setting, `callback_data` makes the data telling the
+    /// callback handler which setting the button toggles.
+    pub fn keyboard(
+        &self,
+        callback_data: impl Fn(Setting) -> String,
+    ) -> Vec<Vec<InlineKeyboardButton>> {
+        Setting::ALL
+            .iter()
+            .map(|setting| {
+                vec![InlineKeyboardButton::callback(
+                    self.button_text(*setting),
+                    callback_data(*setting),
+                )]
+            })
+            .collect()
+    }
+}
+
+/// Settings of the chat, defaults when the chat never changed them or redis is unavailable.
+pub async fn get(chat_id: ChatId) -> ChatSettings {
+    if !storage::is_configured() {
+        return MEMORY_SETTINGS
+            .lock()
+            .ok()
+            .and_then(|settings| settings.get(&chat_id).cloned())
+            .unwrap_or_default();
+    }
+
+    let payload: Option<String> = match storage::connection().await {
+        Ok(mut con) => storage::run(con.get(format!("{}:{}", SETTINGS_KEY, chat_id.0)))
+            .await
+            .unwrap_or_else(|err| {
+                log::warn!("failed to read settings: {}", err);
+                None
+            }),
+        Err(err) => {
+            log::warn!("failed to read settings: {}", err);
+            None
+        }
+    };
+
+    payload
+        .and_then(|payload| serde_json::from_str(&payload).ok())
+        .unwrap_or_default()
+}
+
+pub async fn save(chat_id: ChatId, settings: &ChatSettings) -> Result<(), TwideoError> {
+    if !storage::is_configured() {
+        if let Ok(mut memory_settings) = MEMORY_SETTINGS.lock() {
+            memory_settings.insert(chat_id, settings.clone());
+        }
+        return Ok(());
+    }
+
+    let payload = serde_json::to_string(settings)?;
+    let mut con = storage::connection().await?;
+
+    storage::run(con.set(format!("{}:{}", SETTINGS_KEY, chat_id.0), payload)).await
+}
@@ -0,0 +1,171 @@
+mod nitter;
+mod syndication;
+mod token_pool;
+mod twitter_v1;
+mod twitter_v2;
+
+use crate::{errors::TwideoError, helpers::TwitDetails, metrics};
+use async_trait::async_trait;
+use std::env;
+use token_pool::TokenPool;
+
+pub type SourceResult<T> = Result<T, TwideoError>;
+
+/// Backend able to turn a tweet id into `TwitDetails`.
+///
+/// Transient errors (see `TwideoError::is_transient`) let the next configured backend
+/// have a go before we give up.
+#[async_trait]
+pub trait TweetSource: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn fetch_tweet(&self, id: u64) -> SourceResult<TwitDetails>;
+
+    /// Ids of the author's self-replies in the conversation, in reading order.
+    async fn fetch_conversation(
+        &self,
+        _conversation_id: u64,
+        _user_id: u64,
+    ) -> SourceResult<Vec<u64>> {
+        Ok(vec![])
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref TWITTER_BEARER_TOKENS: TokenPool = TokenPool::from_env();
+
+    // comma separated list of backends tried in order, e.g. "twitter_v2,syndication,nitter"
+    static ref TWEET_SOURCES: Vec<Box<dyn TweetSource>> = env::var("TWEET_SOURCES")
+        .unwrap_or_else(|_| twitter_v2::NAME.to_string())
+        .split(',')
+        .filter_map(|name| build_source(name.trim()))
+        .collect();
+}
+
+fn build_source(name: &str) -> Option<Box<dyn TweetSource>> {
+    match name {
+        twitter_v2::NAME => Some(Box::new(twitter_v2::TwitterV2)),
+        twitter_v1::NAME => Some(Box::new(twitter_v1::TwitterV1)),
+        syndication::NAME => Some(Box::new(syndication::Syndication)),
+        nitter::NAME => Some(Box::new(nitter::Nitter)),
+        "" => None,
+        _ => {
+            log::warn!("Unknown tweet source {}", name);
+            None
+        }
+    }
+}
+
+/// Path of the url with the ids replaced, so all the tweet lookups share one rate limit.
+fn rate_limit_endpoint(url: &str) -> String {
+    let path = url.split_once("://").map_or(url, |(_, rest)| {
+        rest.find('/').map_or("", |start| &rest[start..])
+    });
+
+    path.split(['?', '#'])
+        .next()
+        .unwrap_or_default()
+        .split('/')
+        .map(|segment| {
+            if !segment.is_empty() && segment.bytes().all(|b| b.is_ascii_digit()) {
+                ":id"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// GET authorized with a bearer token from the pool. Exhausted and revoked tokens are
+/// swapped for the next usable one, so a single bad token doesn't fail the request.
+/// The response returned is left for `check_status` to count, the retried ones are
+/// counted here.
+pub(crate) async fn authorized_get(source: &str, url: &str) -> SourceResult<reqwest::Response> {
+    let client = reqwest::Client::new();
+    let attempts = TWITTER_BEARER_TOKENS.len().max(1);
+    let endpoint = rate_limit_endpoint(url);
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
+
+        let token = TWITTER_BEARER_TOKENS.acquire(&endpoint)?;
+
+        let response = client
+            .get(url)
+            .header("AUTHORIZATION", format!("Bearer {}", token))
+            .send()
+            .await?;
+
+        TWITTER_BEARER_TOKENS.report(&token, &endpoint, &response);
+
+        let status = response.status().as_u16();
+
+        if (status == 401 || status == 429) && attempt < attempts {
+            record_response(source, &response);
+            log::info!("Status {}, retrying with another token", status);
+            continue;
+        }
+
+        return Ok(response);
+    }
+}
+
+fn record_response(source: &str, response: &reqwest::Response) {
+    metrics::UPSTREAM_RESPONSES
+        .with_label_values(&[source, response.status().as_str()])
+        .inc();
+}
+
+/// Maps the HTTP status of an upstream response to the matching error.
+pub(crate) fn check_status(source: &str, response: &reqwest::Response) -> SourceResult<()> {
+    log::info!("Status {}", response.status().as_u16());
+
+    record_response(source, response);
+
+    match response.status().as_u16() {
+        200..=299 => Ok(()),
+        401 | 403 => Err(TwideoError::Unauthorized),
+        404 => Err(TwideoError::NotFound),
+        429 => Err(TwideoError::RateLimited {
+            reset: response
+                .headers()
+                .get("x-rate-limit-reset")
+                .and_then(|reset| reset.to_str().ok())
+                .and_then(|reset| reset.parse().ok()),
+        }),
+        code => Err(TwideoError::Upstream(format!("responded with {}", code))),
+    }
+}
+
+/// Asks every configured backend in order until one of them returns the tweet.
+pub async fn fetch_tweet(id: u64) -> SourceResult<TwitDetails> {
+    let mut last_error = TwideoError::Upstream("no tweet sources configured".to_string());
+
+    for source in TWEET_SOURCES.iter() {
+        match source.fetch_tweet(id).await {
+            Ok(twitter_data) => return Ok(twitter_data),
+            Err(err) if err.is_transient() => {
+                log::warn!("{} failed: {}, trying next source", source.name(), err);
+                last_error = err;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Same failover as `fetch_tweet`: the first backend that knows the thread wins.
+pub async fn fetch_conversation(conversation_id: u64, user_id: u64) -> Vec<u64> {
+    for source in TWEET_SOURCES.iter() {
+        match source.fetch_conversation(conversation_id, user_id).await {
+            Ok(thread_ids) if !thread_ids.is_empty() => return thread_ids,
+            Ok(_) => (),
+            Err(err) => log::warn!("{} failed to fetch thread: {}", source.name(), err),
+        }
+    }
+
+    vec![]
+}
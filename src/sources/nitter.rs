@@ -0,0 +1,124 @@
+use super::{check_status, SourceResult, TweetSource};
+use crate::{
+    errors::TwideoError,
+    helpers::{build_caption, push_photo, push_video, unescape_html, TwitDetails, VideoMetadata},
+};
+use async_trait::async_trait;
+use regex::Regex;
+use std::env;
+use twitter_video_dl::serde_schemes::*;
+
+pub const NAME: &str = "nitter";
+
+lazy_static::lazy_static! {
+    static ref NITTER_URL: String = env::var("NITTER_URL").unwrap_or_else(|_| "".to_string());
+    static ref FULLNAME_RE: Regex = Regex::new(r#"class="fullname"[^>]*title="([^"]*)""#).unwrap();
+    static ref USERNAME_RE: Regex = Regex::new(r#"class="username"[^>]*title="@([^"]*)""#).unwrap();
+    static ref CONTENT_RE: Regex = Regex::new(r#"(?s)<div class="tweet-content[^"]*"[^>]*>(.*?)</div>"#).unwrap();
+    static ref IMAGE_RE: Regex = Regex::new(r#"href="/pic/orig/([^"]+)""#).unwrap();
+    static ref VIDEO_RE: Regex = Regex::new(r#"(?s)<video[^>]*poster="([^"]*)"[^>]*>\s*<source src="([^"]+)""#).unwrap();
+    static ref TAG_RE: Regex = Regex::new(r"<[^>]+>").unwrap();
+}
+
+/// Scrapes the tweet page of a Nitter instance configured with `NITTER_URL`.
+pub struct Nitter;
+
+/// Nitter proxies media through itself, so map paths back to absolute urls.
+fn absolute_url(path: &str) -> String {
+    if path.starts_with("http") {
+        path.to_string()
+    } else {
+        format!("{}{}", NITTER_URL.trim_end_matches('/'), path)
+    }
+}
+
+#[async_trait]
+impl TweetSource for Nitter {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    async fn fetch_tweet(&self, twitter_id: u64) -> SourceResult<TwitDetails> {
+        if NITTER_URL.is_empty() {
+            return Err(TwideoError::Upstream("NITTER_URL is not set".to_string()));
+        }
+
+        let response = reqwest::Client::new()
+            .get(format!(
+                "{}/i/status/{}",
+                NITTER_URL.trim_end_matches('/'),
+                twitter_id
+            ))
+            .send()
+            .await?;
+
+        check_status(NAME, &response)?;
+
+        let page = response.text().await?;
+
+        // everything before the main tweet belongs to the replied-to tweets
+        let main_tweet = page
+            .find("class=\"main-tweet\"")
+            .map(|index| &page[index..])
+            .ok_or_else(|| TwideoError::Upstream("nitter page has no main tweet".to_string()))?;
+
+        // and everything after it to the replies
+        let main_tweet = main_tweet
+            .find("class=\"replies\"")
+            .map(|index| &main_tweet[..index])
+            .unwrap_or(main_tweet);
+
+        let capture = |re: &Regex| -> String {
+            re.captures(main_tweet)
+                .and_then(|c| c.get(1))
+                .map(|m| m.as_str().to_string())
+                .unwrap_or_default()
+        };
+
+        let name = unescape_html(&capture(&FULLNAME_RE));
+        let username = capture(&USERNAME_RE);
+        // still escaped, build_caption unescapes it once
+        let tweet_text = TAG_RE.replace_all(&capture(&CONTENT_RE), "").to_string();
+
+        let mut twitter_media = Vec::new();
+
+        for image in IMAGE_RE.captures_iter(main_tweet) {
+            let path = image[1].replace("%2F", "/");
+            push_photo(
+                &mut twitter_media,
+                &format!("https://pbs.twimg.com/{}", path),
+                None,
+            );
+        }
+
+        for video in VIDEO_RE.captures_iter(main_tweet) {
+            push_video(
+                &mut twitter_media,
+                "video",
+                &[Variant {
+                    bit_rate: None,
+                    content_type: "video/mp4".to_string(),
+                    url: absolute_url(&unescape_html(&video[2])),
+                }],
+                &absolute_url(&unescape_html(&video[1])),
+                VideoMetadata::default(),
+            );
+        }
+
+        Ok(TwitDetails {
+            caption: build_caption(&tweet_text, None, !twitter_media.is_empty(), None),
+            twitter_media,
+            name,
+            username,
+            id: twitter_id,
+            next: 1,
+            conversation_id: twitter_id,
+            thread_count: 0,
+            user_id: 0,
+            quoted: None,
+            replied_to: None,
+            poll: None,
+            card: None,
+        })
+    }
+}
@@ -0,0 +1,172 @@
+use super::{check_status, SourceResult, TweetSource};
+use crate::{
+    errors::TwideoError,
+    helpers::{build_caption, push_photo, push_video, TwitDetails, VideoMetadata},
+};
+use async_trait::async_trait;
+use std::f64::consts::PI;
+use twitter_video_dl::serde_schemes::*;
+
+pub const NAME: &str = "syndication";
+
+lazy_static::lazy_static! {
+    static ref TWITTER_SYNDICATION_URL: &'static str = "https://cdn.syndication.twimg.com/tweet-result";
+}
+
+/// The JSON behind embedded tweets; needs no credentials but knows nothing about threads.
+pub struct Syndication;
+
+/// `Number.prototype.toString(36)` as V8 does it: fraction digits go only as far as the
+/// precision of the double, and the last one is rounded half to even.
+fn to_radix_36(value: f64) -> String {
+    const DIGITS: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+    const RADIX: f64 = 36.0;
+
+    let mut integer = value.floor();
+    let mut fraction = value - integer;
+    // half the distance to the next double, digits below it are noise
+    let mut delta = (0.5 * (f64::from_bits(value.to_bits() + 1) - value)).max(f64::from_bits(1));
+    let mut fraction_digits: Vec<usize> = Vec::new();
+
+    if fraction >= delta {
+        loop {
+            fraction *= RADIX;
+            delta *= RADIX;
+
+            let digit = fraction as usize;
+            fraction_digits.push(digit);
+            fraction -= digit as f64;
+
+            if (fraction > 0.5 || (fraction == 0.5 && digit & 1 == 1)) && fraction + delta > 1.0 {
+                // round up, carrying over the digits that are already written
+                loop {
+                    match fraction_digits.pop() {
+                        Some(digit) if digit + 1 < DIGITS.len() => {
+                            fraction_digits.push(digit + 1);
+                            break;
+                        }
+                        Some(_) => (),
+                        None => {
+                            integer += 1.0;
+                            break;
+                        }
+                    }
+                }
+                break;
+            }
+
+            if fraction < delta {
+                break;
+            }
+        }
+    }
+
+    // the integer part of the token is way below 2^53, so every digit of it is exact
+    let mut token = Vec::new();
+
+    loop {
+        let remainder = integer % RADIX;
+        token.insert(0, DIGITS[remainder as usize]);
+        integer = (integer - remainder) / RADIX;
+        if integer <= 0.0 {
+            break;
+        }
+    }
+
+    token.extend(fraction_digits.into_iter().map(|digit| DIGITS[digit]));
+
+    String::from_utf8(token).unwrap_or_default()
+}
+
+/// Mirrors the embed widget's `((id / 1e15) * Math.PI).toString(36).replace(/(0+|\.)/g, '')`.
+fn syndication_token(id: u64) -> String {
+    to_radix_36((id as f64 / 1e15) * PI).replace('0', "")
+}
+
+#[async_trait]
+impl TweetSource for Syndication {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    async fn fetch_tweet(&self, twitter_id: u64) -> SourceResult<TwitDetails> {
+        let response = reqwest::Client::new()
+            .get(format!(
+                "{}?id={}&token={}",
+                &*TWITTER_SYNDICATION_URL,
+                twitter_id,
+                syndication_token(twitter_id)
+            ))
+            .send()
+            .await?;
+
+        check_status(NAME, &response)?;
+
+        let body = response.bytes().await?;
+
+        // deleted and age restricted tweets come back as a tombstone without any content
+        let kind = serde_json::from_slice::<SyndicationKind>(&body)?;
+        if kind.typename.as_deref() == Some("TweetTombstone") {
+            return Err(TwideoError::Deleted);
+        }
+
+        let tweet = serde_json::from_slice::<SyndicationBody>(&body)?;
+
+        let mut twitter_media = Vec::new();
+
+        for media in tweet.media_details.as_deref().unwrap_or_default() {
+            match &media.video_info {
+                Some(video_info) => push_video(
+                    &mut twitter_media,
+                    &media.r#type,
+                    &video_info.variants,
+                    &media.media_url_https,
+                    VideoMetadata {
+                        width: media.original_info.as_ref().map(|info| info.width),
+                        height: media.original_info.as_ref().map(|info| info.height),
+                        duration: video_info.duration_millis.map(|duration| duration / 1000),
+                    },
+                ),
+                None => push_photo(
+                    &mut twitter_media,
+                    &media.media_url_https,
+                    media.ext_alt_text.as_deref(),
+                ),
+            }
+        }
+
+        Ok(TwitDetails {
+            caption: build_caption(&tweet.text, None, !twitter_media.is_empty(), None),
+            twitter_media,
+            name: tweet.user.name.to_string(),
+            username: tweet.user.screen_name.to_string(),
+            id: twitter_id,
+            next: 1,
+            conversation_id: twitter_id,
+            thread_count: 0,
+            user_id: tweet.user.id_str.parse().unwrap_or(0),
+            quoted: None,
+            replied_to: None,
+            poll: None,
+            card: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_matches_embed_widget() {
+        // computed with the widget expression in node
+        for (id, token) in [
+            (1234567890123456789, "2zqic77uqyk"),
+            (20, "6dq1a2xwd93"),
+            (1650000000000000000, "3zzmlqan8os"),
+            (463440424141459456, "14fxvks611f"),
+        ] {
+            assert_eq!(syndication_token(id), token, "token of {}", id);
+        }
+    }
+}
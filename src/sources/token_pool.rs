@@ -0,0 +1,307 @@
+use crate::{errors::TwideoError, metrics};
+use chrono::Utc;
+use reqwest::header::HeaderMap;
+use std::{collections::HashMap, env, fs, str::FromStr, sync::Mutex};
+
+// how long to rest a token that got 429 without telling us when to come back
+const DEFAULT_RESET_SECONDS: i64 = 60;
+
+#[derive(Default)]
+struct RateLimit {
+    remaining: Option<u32>,
+    reset: Option<i64>,
+}
+
+impl RateLimit {
+    /// Limits from the `x-rate-limit-*` headers, missing when twitter didn't send them.
+    fn from_headers(headers: &HeaderMap) -> Self {
+        RateLimit {
+            remaining: header(headers, "x-rate-limit-remaining"),
+            reset: header(headers, "x-rate-limit-reset"),
+        }
+    }
+
+    fn is_exhausted(&self, now: i64) -> bool {
+        self.remaining == Some(0) && matches!(self.reset, Some(r) if r > now)
+    }
+
+    /// Requests left in the current window, as good as fresh when the window is over.
+    fn left(&self, now: i64) -> u32 {
+        match self.reset {
+            Some(reset) if reset <= now => u32::MAX,
+            _ => self.remaining.unwrap_or(u32::MAX),
+        }
+    }
+}
+
+struct TokenState {
+    token: String,
+    /// Twitter limits every endpoint separately, so the limits are kept per endpoint.
+    limits: HashMap<String, RateLimit>,
+    revoked: bool,
+}
+
+impl TokenState {
+    fn is_available(&self, endpoint: &str, now: i64) -> bool {
+        !self.revoked
+            && !self
+                .limits
+                .get(endpoint)
+                .is_some_and(|limit| limit.is_exhausted(now))
+    }
+
+    fn left(&self, endpoint: &str, now: i64) -> u32 {
+        self.limits
+            .get(endpoint)
+            .map_or(u32::MAX, |limit| limit.left(now))
+    }
+}
+
+/// Bearer tokens with the rate limit state twitter reports for each of them.
+pub struct TokenPool {
+    tokens: Mutex<Vec<TokenState>>,
+}
+
+impl TokenPool {
+    /// Reads tokens from `TWITTER_BEARER_TOKENS` (comma separated), the file in
+    /// `TWITTER_BEARER_TOKENS_FILE` (one per line) and the legacy `TWITTER_BEARER_TOKEN`
+    /// and `TWITTER_BEARER_TOKEN2` variables.
+    pub fn from_env() -> Self {
+        let mut tokens: Vec<String> = Vec::new();
+
+        if let Ok(list) = env::var("TWITTER_BEARER_TOKENS") {
+            tokens.extend(list.split(',').map(|token| token.trim().to_string()));
+        }
+
+        if let Ok(path) = env::var("TWITTER_BEARER_TOKENS_FILE") {
+            match fs::read_to_string(&path) {
+                Ok(content) => tokens.extend(content.lines().map(|token| token.trim().to_string())),
+                Err(err) => log::warn!("failed to read {}: {}", path, err),
+            }
+        }
+
+        for name in ["TWITTER_BEARER_TOKEN", "TWITTER_BEARER_TOKEN2"] {
+            if let Ok(token) = env::var(name) {
+                tokens.push(token.trim().to_string());
+            }
+        }
+
+        TokenPool::new(tokens)
+    }
+
+    fn new(tokens: Vec<String>) -> Self {
+        let mut states: Vec<TokenState> = Vec::new();
+
+        for token in tokens {
+            if !token.is_empty() && !states.iter().any(|state| state.token == token) {
+                states.push(TokenState {
+                    token,
+                    limits: HashMap::new(),
+                    revoked: false,
+                });
+            }
+        }
+
+        log::info!("Loaded {} bearer tokens", states.len());
+
+        TokenPool {
+            tokens: Mutex::new(states),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.lock().map(|tokens| tokens.len()).unwrap_or(0)
+    }
+
+    /// Picks the usable token with the most requests to `endpoint` left in its current window.
+    pub fn acquire(&self, endpoint: &str) -> Result<String, TwideoError> {
+        let tokens = self
+            .tokens
+            .lock()
+            .map_err(|_| TwideoError::Upstream("token pool is poisoned".to_string()))?;
+        let now = Utc::now().timestamp();
+
+        let best = tokens
+            .iter()
+            .filter(|state| state.is_available(endpoint, now))
+            .max_by_key(|state| state.left(endpoint, now));
+
+        if let Some(state) = best {
+            return Ok(state.token.to_string());
+        }
+
+        if tokens.iter().all(|state| state.revoked) {
+            return Err(TwideoError::Unauthorized);
+        }
+
+        Err(TwideoError::RateLimited {
+            reset: tokens
+                .iter()
+                .filter(|state| !state.revoked)
+                .filter_map(|state| state.limits.get(endpoint)?.reset)
+                .min(),
+        })
+    }
+
+    /// Updates the token state from the `x-rate-limit-*` headers of its response.
+    pub fn report(&self, token: &str, endpoint: &str, response: &reqwest::Response) {
+        self.update(
+            token,
+            endpoint,
+            response.status().as_u16(),
+            RateLimit::from_headers(response.headers()),
+        );
+    }
+
+    fn update(&self, token: &str, endpoint: &str, status: u16, reported: RateLimit) {
+        let mut tokens = match self.tokens.lock() {
+            Ok(tokens) => tokens,
+            Err(_) => return,
+        };
+
+        let state = match tokens.iter_mut().find(|state| state.token == token) {
+            Some(state) => state,
+            None => return,
+        };
+
+        let limit = state.limits.entry(endpoint.to_string()).or_default();
+
+        if reported.remaining.is_some() {
+            limit.remaining = reported.remaining;
+        }
+
+        if reported.reset.is_some() {
+            limit.reset = reported.reset;
+        }
+
+        match status {
+            401 => {
+                log::warn!(
+                    "Bearer token ...{} is revoked, removing it",
+                    token_suffix(token)
+                );
+                state.revoked = true;
+            }
+            429 => {
+                metrics::TOKEN_RATE_LIMITED
+                    .with_label_values(&[token_suffix(token)])
+                    .inc();
+
+                let now = Utc::now().timestamp();
+
+                limit.remaining = Some(0);
+                if !matches!(limit.reset, Some(reset) if reset > now) {
+                    limit.reset = Some(now + DEFAULT_RESET_SECONDS);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Enough of the token to tell them apart in logs without leaking it.
+fn token_suffix(token: &str) -> &str {
+    token
+        .get(token.len().saturating_sub(4)..)
+        .unwrap_or_default()
+}
+
+fn header<T: FromStr>(headers: &HeaderMap, name: &str) -> Option<T> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENDPOINT: &str = "/2/tweets";
+
+    fn limit(remaining: u32, reset: i64) -> RateLimit {
+        RateLimit {
+            remaining: Some(remaining),
+            reset: Some(reset),
+        }
+    }
+
+    fn pool(tokens: &[&str]) -> TokenPool {
+        TokenPool::new(tokens.iter().map(|token| token.to_string()).collect())
+    }
+
+    #[test]
+    fn limits_are_read_from_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-rate-limit-remaining", "42".parse().unwrap());
+        headers.insert("x-rate-limit-reset", "1700000000".parse().unwrap());
+
+        let limit = RateLimit::from_headers(&headers);
+
+        assert_eq!(limit.remaining, Some(42), "remaining");
+        assert_eq!(limit.reset, Some(1700000000), "reset");
+
+        let limit = RateLimit::from_headers(&HeaderMap::new());
+
+        assert_eq!(limit.remaining, None, "missing remaining");
+        assert_eq!(limit.reset, None, "missing reset");
+    }
+
+    #[test]
+    fn exhausted_token_is_skipped_until_reset() {
+        let pool = pool(&["first", "second"]);
+        let now = Utc::now().timestamp();
+
+        pool.update("first", ENDPOINT, 200, limit(0, now + 60));
+        pool.update("second", ENDPOINT, 200, limit(10, now + 60));
+
+        assert_eq!(pool.acquire(ENDPOINT).unwrap(), "second", "before reset");
+
+        pool.update("second", ENDPOINT, 200, limit(0, now + 30));
+
+        assert!(
+            matches!(pool.acquire(ENDPOINT), Err(TwideoError::RateLimited { reset: Some(reset) }) if reset == now + 30),
+            "all exhausted"
+        );
+        assert!(
+            pool.acquire("/2/tweets/search/recent").is_ok(),
+            "other endpoint"
+        );
+
+        pool.update("first", ENDPOINT, 200, limit(0, now - 1));
+
+        assert_eq!(pool.acquire(ENDPOINT).unwrap(), "first", "after reset");
+    }
+
+    #[test]
+    fn token_with_most_requests_left_is_preferred() {
+        let pool = pool(&["first", "second", "third"]);
+        let now = Utc::now().timestamp();
+
+        pool.update("first", ENDPOINT, 200, limit(5, now + 60));
+        pool.update("second", ENDPOINT, 200, limit(50, now + 60));
+        pool.update("third", ENDPOINT, 200, limit(20, now + 60));
+
+        assert_eq!(pool.acquire(ENDPOINT).unwrap(), "second", "most left");
+
+        pool.update("second", ENDPOINT, 429, RateLimit::default());
+
+        assert_eq!(pool.acquire(ENDPOINT).unwrap(), "third", "after 429");
+    }
+
+    #[test]
+    fn unauthorized_token_is_revoked() {
+        let pool = pool(&["first", "second"]);
+
+        pool.update("first", ENDPOINT, 401, RateLimit::default());
+
+        assert_eq!(pool.acquire(ENDPOINT).unwrap(), "second", "one revoked");
+
+        pool.update("second", ENDPOINT, 401, RateLimit::default());
+
+        assert!(
+            matches!(pool.acquire(ENDPOINT), Err(TwideoError::Unauthorized)),
+            "all revoked"
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use super::{authorized_get, check_status, SourceResult, TweetSource};
+use crate::{
+    errors::TwideoError,
+    helpers::{build_caption, push_photo, push_video, TwitDetails, VideoMetadata},
+};
+use async_trait::async_trait;
+use twitter_video_dl::serde_schemes::*;
+
+pub const NAME: &str = "twitter_v1";
+
+lazy_static::lazy_static! {
+    static ref TWITTER_STATUS_URL: &'static str = "https://api.twitter.com/1.1/statuses/show.json?extended_entities=true&tweet_mode=extended&include_ext_alt_text=true&id=";
+}
+
+/// The legacy v1.1 `statuses/show` endpoint, still served for some app tokens.
+pub struct TwitterV1;
+
+// "Sorry, you are not authorized to see this status."
+const PROTECTED_STATUS: u32 = 179;
+
+/// v1.1 answers 403 for tweets of protected accounts, but also for apps whose access
+/// tier doesn't include v1.1 (code 453), which is up to the next source to handle.
+fn status_error(errors: &[StatusError]) -> TwideoError {
+    match errors.first() {
+        Some(error) if error.code == PROTECTED_STATUS => TwideoError::Protected,
+        Some(error) => TwideoError::Upstream(format!(
+            "v1.1 error {}: {}",
+            error.code,
+            error.message.as_deref().unwrap_or_default()
+        )),
+        None => TwideoError::Unauthorized,
+    }
+}
+
+#[async_trait]
+impl TweetSource for TwitterV1 {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    async fn fetch_tweet(&self, twitter_id: u64) -> SourceResult<TwitDetails> {
+        let response =
+            authorized_get(NAME, &format!("{}{}", &*TWITTER_STATUS_URL, twitter_id)).await?;
+
+        if let Err(err) = check_status(NAME, &response) {
+            if response.status().as_u16() != 403 {
+                return Err(err);
+            }
+
+            let body = response.json::<StatusErrors>().await.unwrap_or_default();
+            return Err(status_error(&body.errors));
+        }
+
+        let status = response.json::<StatusBody>().await?;
+
+        let mut twitter_media = Vec::new();
+
+        for media in status
+            .extended_entities
+            .as_ref()
+            .map(|entities| entities.media.as_slice())
+            .unwrap_or_default()
+        {
+            match &media.video_info {
+                Some(video_info) => push_video(
+                    &mut twitter_media,
+                    &media.r#type,
+                    &video_info.variants,
+                    &media.media_url_https,
+                    VideoMetadata {
+                        width: media.original_info.as_ref().map(|info| info.width),
+                        height: media.original_info.as_ref().map(|info| info.height),
+                        duration: video_info.duration_millis.map(|duration| duration / 1000),
+                    },
+                ),
+                None => push_photo(
+                    &mut twitter_media,
+                    &media.media_url_https,
+                    media.ext_alt_text.as_deref(),
+                ),
+            }
+        }
+
+        let tweet_text = status
+            .full_text
+            .as_deref()
+            .or(status.text.as_deref())
+            .unwrap_or_default();
+
+        Ok(TwitDetails {
+            caption: build_caption(tweet_text, None, !twitter_media.is_empty(), None),
+            twitter_media,
+            name: status.user.name.to_string(),
+            username: status.user.screen_name.to_string(),
+            id: twitter_id,
+            next: 1,
+            // v1.1 doesn't know about conversations, so the tweet is its own thread root
+            conversation_id: twitter_id,
+            thread_count: 0,
+            user_id: status.user.id_str.parse().unwrap_or(0),
+            quoted: None,
+            replied_to: None,
+            poll: None,
+            card: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_of(body: &str) -> TwideoError {
+        status_error(&serde_json::from_str::<StatusErrors>(body).unwrap().errors)
+    }
+
+    #[test]
+    fn only_protected_status_is_protected() {
+        assert!(matches!(
+            error_of(
+                r#"{"errors":[{"code":179,"message":"Sorry, you are not authorized to see this status."}]}"#
+            ),
+            TwideoError::Protected
+        ));
+        assert!(error_of(r#"{"errors":[{"code":453,"message":"You currently have access to a subset of Twitter API v2 endpoints"}]}"#).is_transient());
+        assert!(error_of("{}").is_transient());
+    }
+}
@@ -0,0 +1,396 @@
+use super::{authorized_get, check_status, SourceResult, TweetSource};
+use crate::{
+    errors::TwideoError,
+    helpers::{
+        build_caption, push_photo, push_video, ReferencedTweet, TwitDetails, TwitterCard,
+        TwitterMedia, TwitterPoll, TwitterPollOption, VideoMetadata,
+    },
+};
+use async_trait::async_trait;
+use std::{cmp::Reverse, collections::HashMap};
+use twitter_video_dl::serde_schemes::*;
+
+pub const NAME: &str = "twitter_v2";
+// search answers with at most 100 tweets a page, so this is enough for 1000 tweets long threads
+const MAX_SEARCH_PAGES: usize = 10;
+
+lazy_static::lazy_static! {
+    static ref TWITTER_MULTIMEDIA_URL: &'static str = "https://api.twitter.com/2/tweets";
+    static ref TWITTER_SEARCH_URL: &'static str = "https://api.twitter.com/2/tweets/search/recent";
+    static ref TWITTER_EXPANSIONS_PARAMS: &'static str = "expansions=attachments.media_keys,author_id,referenced_tweets.id,referenced_tweets.id.author_id,referenced_tweets.id.attachments.media_keys,attachments.poll_ids&poll.fields=options,voting_status,end_datetime&media.fields=url,variants,preview_image_url,width,height,duration_ms,alt_text&user.fields=name";
+}
+
+/// The author may reply to themselves more than once, which makes the thread a tree, so
+/// follow the longest chain of replies starting at the conversation root. Returns the
+/// thread without the root.
+fn longest_chain(conversation_id: u64, tweets: &[ThreadSearchData]) -> Vec<u64> {
+    let parents: HashMap<u64, u64> = tweets
+        .iter()
+        .filter_map(|tweet| {
+            let parent = tweet
+                .referenced_tweets
+                .iter()
+                .find(|reference| reference.r#type == "replied_to")?;
+
+            Some((parse_id(Some(&tweet.id))?, parse_id(Some(&parent.id))?))
+        })
+        .collect();
+
+    // replies are always newer than the tweets they reply to, so going from the newest
+    // tweet to the oldest one sees all the replies before the tweet itself
+    let mut ids: Vec<u64> = parents.keys().copied().collect();
+    ids.sort_unstable_by_key(|id| Reverse(*id));
+
+    // length of the longest chain below the tweet and the reply it continues with
+    let mut longest: HashMap<u64, (usize, u64)> = HashMap::new();
+
+    for id in ids {
+        let length = longest.get(&id).map_or(0, |(length, _)| *length) + 1;
+        let parent = longest.entry(parents[&id]).or_insert((0, id));
+
+        // the older branch wins a tie
+        if length >= parent.0 {
+            *parent = (length, id);
+        }
+    }
+
+    let mut thread_ids = vec![];
+    let mut current = conversation_id;
+
+    while let Some((_, next)) = longest.get(&current) {
+        // ids can't go in circles unless twitter answers nonsense, but better safe
+        if thread_ids.len() >= parents.len() {
+            break;
+        }
+
+        thread_ids.push(*next);
+        current = *next;
+    }
+
+    thread_ids
+}
+
+/// The official v2 API authorized with app bearer tokens.
+pub struct TwitterV2;
+
+fn parse_id(id: Option<&str>) -> Option<u64> {
+    id.and_then(|id| id.parse::<u64>().ok())
+}
+
+fn find_user<'a>(users: &'a [TwitterUser], tweet: &MultimediaData) -> Option<&'a TwitterUser> {
+    users
+        .iter()
+        .find(|user| user.id.is_some() && user.id == tweet.author_id)
+}
+
+/// Long tweets come truncated in `text`, the whole text and its entities are in `note_tweet`.
+fn full_text(tweet: &MultimediaData) -> (&str, Option<&Entities>) {
+    match &tweet.note_tweet {
+        Some(note_tweet) => (&note_tweet.text, note_tweet.entities.as_ref()),
+        None => (
+            tweet.text.as_deref().unwrap_or_default(),
+            tweet.entities.as_ref(),
+        ),
+    }
+}
+
+/// `includes` holds the media of the referenced tweets as well, so only the media
+/// attached to the tweet itself is picked.
+fn push_attached_media(
+    twitter_media: &mut Vec<TwitterMedia>,
+    media_set: &[Media],
+    tweet: &MultimediaData,
+) {
+    let media_keys = match &tweet.attachments {
+        Some(attachments) => &attachments.media_keys,
+        None => return,
+    };
+
+    for media_key in media_keys {
+        let media = match media_set
+            .iter()
+            .find(|media| media.media_key.as_ref() == Some(media_key))
+        {
+            Some(media) => media,
+            None => continue,
+        };
+
+        if media.r#type == "video" || media.r#type == "animated_gif" {
+            push_video(
+                twitter_media,
+                &media.r#type,
+                media.variants.as_deref().unwrap_or_default(),
+                media.preview_image_url.as_deref().unwrap_or_default(),
+                VideoMetadata {
+                    width: media.width,
+                    height: media.height,
+                    duration: media.duration_ms.map(|duration| duration / 1000),
+                },
+            );
+        } else if media.r#type == "photo" {
+            if let Some(url) = &media.url {
+                push_photo(twitter_media, url, media.alt_text.as_deref());
+            }
+        }
+    }
+}
+
+fn attached_poll(polls: &[Poll], tweet: &MultimediaData) -> Option<TwitterPoll> {
+    let poll_id = tweet.attachments.as_ref()?.poll_ids.first()?;
+    let poll = polls.iter().find(|poll| &poll.id == poll_id)?;
+
+    Some(TwitterPoll {
+        options: poll
+            .options
+            .iter()
+            .map(|option| TwitterPollOption {
+                label: option.label.to_string(),
+                votes: option.votes,
+            })
+            .collect(),
+        open: poll.voting_status.as_deref() == Some("open"),
+        end_datetime: poll.end_datetime.clone(),
+    })
+}
+
+/// Twitter shows the card for the last link of the tweet, if it found any details for it.
+fn link_card(entities: Option<&Entities>) -> Option<TwitterCard> {
+    let url = entities?
+        .urls
+        .iter()
+        .rev()
+        .find(|url| url.media_key.is_none() && url.title.is_some())?;
+
+    Some(TwitterCard {
+        url: url
+            .unwound_url
+            .as_ref()
+            .or(url.expanded_url.as_ref())
+            .unwrap_or(&url.url)
+            .to_string(),
+        title: url.title.clone().unwrap_or_default(),
+        description: url
+            .description
+            .clone()
+            .filter(|description| !description.is_empty()),
+        image: url
+            .images
+            .as_ref()
+            .and_then(|images| images.first())
+            .map(|image| image.url.to_string()),
+    })
+}
+
+fn referenced_tweet(
+    tweet: &MultimediaData,
+    users: &[TwitterUser],
+    media_set: &[Media],
+) -> Option<Box<ReferencedTweet>> {
+    let user = find_user(users, tweet)?;
+    let mut twitter_media = Vec::new();
+
+    push_attached_media(&mut twitter_media, media_set, tweet);
+
+    let (text, entities) = full_text(tweet);
+
+    Some(Box::new(ReferencedTweet {
+        id: parse_id(tweet.id.as_deref())?,
+        text: build_caption(text, entities, !twitter_media.is_empty(), None),
+        name: user.name.to_string(),
+        username: user.username.to_string(),
+        twitter_media,
+    }))
+}
+
+/// v2 answers 200 with an `errors` list instead of `data` when it can't show the tweet.
+fn api_error(errors: &[ApiError]) -> TwideoError {
+    let error = match errors.first() {
+        Some(error) => error,
+        None => return TwideoError::Upstream("response without data".to_string()),
+    };
+
+    match error.r#type.as_deref().unwrap_or_default() {
+        t if t.ends_with("/resource-not-found") => TwideoError::NotFound,
+        t if t.ends_with("/not-authorized-for-resource") => TwideoError::Protected,
+        t if t.ends_with("/resource-unavailable") => TwideoError::Deleted,
+        _ => TwideoError::Upstream(error.detail.clone().unwrap_or_default()),
+    }
+}
+
+#[async_trait]
+impl TweetSource for TwitterV2 {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    async fn fetch_tweet(&self, twitter_id: u64) -> SourceResult<TwitDetails> {
+        let url = format!(
+            "{}/{}?tweet.fields=conversation_id,referenced_tweets,attachments,note_tweet,entities&{}",
+            &*TWITTER_MULTIMEDIA_URL, twitter_id, &*TWITTER_EXPANSIONS_PARAMS
+        );
+        let multimedia_response = authorized_get(NAME, &url).await?;
+
+        check_status(NAME, &multimedia_response)?;
+
+        let multimedia = multimedia_response.json::<MultimediaBody>().await?;
+
+        let data = match multimedia.data {
+            Some(data) => data,
+            None => return Err(api_error(multimedia.errors.as_deref().unwrap_or_default())),
+        };
+
+        let includes = multimedia.includes.as_ref();
+        let users = includes
+            .map(|includes| includes.users.as_slice())
+            .unwrap_or_default();
+        let media_set = includes
+            .and_then(|includes| includes.media.as_deref())
+            .unwrap_or_default();
+        let tweets = includes
+            .and_then(|includes| includes.tweets.as_deref())
+            .unwrap_or_default();
+        let polls = includes
+            .and_then(|includes| includes.polls.as_deref())
+            .unwrap_or_default();
+
+        let mut twitter_media: Vec<TwitterMedia> = Vec::new();
+        let conversation_id = parse_id(data.conversation_id.as_deref()).unwrap_or(twitter_id);
+        let user_id = parse_id(data.author_id.as_deref()).unwrap_or(0);
+
+        let (name, username) = match find_user(users, &data).or_else(|| users.first()) {
+            Some(user) => (user.name.to_string(), user.username.to_string()),
+            None => (String::new(), String::new()),
+        };
+
+        push_attached_media(&mut twitter_media, media_set, &data);
+
+        let mut quoted = None;
+        let mut replied_to = None;
+
+        for reference in &data.referenced_tweets {
+            let referenced_tweet = tweets
+                .iter()
+                .find(|tweet| tweet.id.as_deref() == Some(reference.id.as_str()))
+                .and_then(|tweet| referenced_tweet(tweet, users, media_set));
+
+            match reference.r#type.as_str() {
+                "quoted" => quoted = referenced_tweet,
+                "replied_to" => replied_to = referenced_tweet,
+                _ => (),
+            }
+        }
+
+        let (tweet_text, entities) = full_text(&data);
+
+        Ok(TwitDetails {
+            caption: build_caption(
+                tweet_text,
+                entities,
+                !twitter_media.is_empty(),
+                quoted.as_ref().map(|quoted| quoted.id),
+            ),
+            twitter_media,
+            name,
+            username,
+            id: twitter_id,
+            next: 1,
+            conversation_id,
+            thread_count: 0,
+            user_id,
+            poll: attached_poll(polls, &data),
+            // twitter shows the quoted tweet instead of the link card
+            card: if quoted.is_none() {
+                link_card(entities)
+            } else {
+                None
+            },
+            quoted,
+            replied_to,
+        })
+    }
+
+    async fn fetch_conversation(
+        &self,
+        conversation_id: u64,
+        user_id: u64,
+    ) -> SourceResult<Vec<u64>> {
+        let mut search_data = Vec::new();
+        let mut next_token: Option<String> = None;
+
+        for _ in 0..MAX_SEARCH_PAGES {
+            let mut url = format!(
+                "{0}?query=conversation_id:{1} from:{2} to:{2}&tweet.fields=author_id,referenced_tweets&max_results=100",
+                &*TWITTER_SEARCH_URL, conversation_id, user_id
+            );
+            if let Some(next_token) = &next_token {
+                url.push_str(&format!("&next_token={}", next_token));
+            }
+
+            let result = authorized_get(NAME, &url).await?;
+
+            check_status(NAME, &result)?;
+
+            let response_json = result.json::<ThreadSearchResult>().await?;
+            search_data.extend(response_json.data.unwrap_or_default());
+
+            next_token = response_json.meta.and_then(|meta| meta.next_token);
+            if next_token.is_none() {
+                break;
+            }
+        }
+
+        Ok(longest_chain(conversation_id, &search_data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(id: u64, to: u64) -> ThreadSearchData {
+        ThreadSearchData {
+            id: id.to_string(),
+            referenced_tweets: vec![ReferencedTweets {
+                id: to.to_string(),
+                r#type: "replied_to".to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn longest_chain_follows_the_longest_branch() {
+        // 1 ─ 2 ─ 3 ─ 6
+        //   └ 4 ─ 5
+        let tweets = [
+            reply(2, 1),
+            reply(3, 2),
+            reply(4, 1),
+            reply(5, 4),
+            reply(6, 3),
+        ];
+
+        assert_eq!(longest_chain(1, &tweets), vec![2, 3, 6]);
+    }
+
+    #[test]
+    fn longest_chain_prefers_the_older_branch_on_tie() {
+        let tweets = [reply(2, 1), reply(3, 1), reply(4, 2), reply(5, 3)];
+
+        assert_eq!(longest_chain(1, &tweets), vec![2, 4]);
+    }
+
+    #[test]
+    fn longest_chain_skips_stray_replies() {
+        // 7 replies to a tweet the search didn't find, 8 quotes instead of replying
+        let mut tweets = vec![reply(2, 1), reply(3, 2), reply(7, 5)];
+        tweets.push(ThreadSearchData {
+            id: "8".to_string(),
+            referenced_tweets: vec![ReferencedTweets {
+                id: "3".to_string(),
+                r#type: "quoted".to_string(),
+            }],
+        });
+
+        assert_eq!(longest_chain(1, &tweets), vec![2, 3]);
+    }
+}
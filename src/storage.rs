@@ -0,0 +1,88 @@
+use crate::errors::TwideoError;
+use redis::{aio::ConnectionManager, RedisResult};
+use std::{
+    env,
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::{sync::OnceCell, time::timeout};
+
+const REDIS_TIMEOUT: Duration = Duration::from_secs(2);
+// how long to answer right away with an error after failing to connect
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(30);
+
+lazy_static::lazy_static! {
+    pub static ref REDIS_URL: String = env::var("REDIS_URL").unwrap_or_else(|_| "".to_string());
+    static ref CONNECTION: OnceCell<ConnectionManager> = OnceCell::new();
+    static ref LAST_FAILURE: Mutex<Option<Instant>> = Mutex::new(None);
+}
+
+pub fn is_configured() -> bool {
+    !REDIS_URL.is_empty()
+}
+
+/// Shared connection to redis. It's created on the first successful attempt and reconnects
+/// by itself afterwards, so callers just clone it. After a failed attempt the next one
+/// waits for `RECONNECT_INTERVAL`, so an unreachable redis doesn't queue every handler
+/// behind the connection timeout.
+pub async fn connection() -> Result<ConnectionManager, TwideoError> {
+    if !is_configured() {
+        return Err(TwideoError::Cache("REDIS_URL is not set".to_string()));
+    }
+
+    if let Some(connection) = CONNECTION.get() {
+        return Ok(connection.clone());
+    }
+
+    check_backoff()?;
+
+    let connection = CONNECTION
+        .get_or_try_init(|| async {
+            // the handlers that waited for the failed attempt shouldn't repeat it
+            check_backoff()?;
+
+            let client = redis::Client::open(&**REDIS_URL)?;
+            let connection = timeout(REDIS_TIMEOUT, client.get_tokio_connection_manager())
+                .await
+                .map_err(|_| TwideoError::Cache("connection timed out".to_string()))
+                .and_then(|connection| connection.map_err(TwideoError::from));
+
+            if connection.is_err() {
+                if let Ok(mut last_failure) = LAST_FAILURE.lock() {
+                    *last_failure = Some(Instant::now());
+                }
+            }
+
+            connection
+        })
+        .await?;
+
+    Ok(connection.clone())
+}
+
+fn check_backoff() -> Result<(), TwideoError> {
+    let last_failure = LAST_FAILURE
+        .lock()
+        .ok()
+        .and_then(|last_failure| *last_failure);
+
+    match last_failure {
+        Some(failed_at) if failed_at.elapsed() < RECONNECT_INTERVAL => Err(TwideoError::Cache(
+            "redis is unreachable, waiting to reconnect".to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Runs the redis command giving up after `REDIS_TIMEOUT`, so a stuck redis can't hold
+/// the handlers.
+pub async fn run<T, F>(command: F) -> Result<T, TwideoError>
+where
+    F: Future<Output = RedisResult<T>>,
+{
+    timeout(REDIS_TIMEOUT, command)
+        .await
+        .map_err(|_| TwideoError::Cache("command timed out".to_string()))?
+        .map_err(TwideoError::from)
+}
@@ -0,0 +1,401 @@
+use crate::{
+    errors::TwideoError,
+    helpers::{unescape_html, TwitDetails},
+    settings::VideoQuality,
+};
+use async_trait::async_trait;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::{env, time::Duration};
+use tokio::sync::OnceCell;
+
+const TITLE_LIMIT: usize = 60;
+const SHORT_NAME: &str = "twideo";
+
+lazy_static::lazy_static! {
+    static ref TELEGRAPH_URL: String = env::var("TELEGRAPH_URL")
+        .unwrap_or_else(|_| "https://api.telegra.ph".to_string());
+    static ref THREAD_UNROLL: String = env::var("THREAD_UNROLL").unwrap_or_else(|_| "telegraph".to_string());
+    static ref HREF_RE: Regex = Regex::new(r#"href=['"]([^'"]*)['"]"#).unwrap();
+    static ref PUBLISHER: Box<dyn ArticlePublisher> = Box::new(Telegraph {
+        api_url: TELEGRAPH_URL.trim_end_matches('/').to_string(),
+        access_token: OnceCell::new(),
+    });
+}
+
+/// Article made from the whole thread, `content` is a list of Telegraph `Node`s.
+pub struct Article {
+    pub title: String,
+    pub author_name: String,
+    pub author_url: String,
+    pub content: Vec<Value>,
+}
+
+/// Publishes articles somewhere telegram can show them with instant view.
+#[async_trait]
+pub trait ArticlePublisher: Send + Sync {
+    /// Returns the url of the published article.
+    async fn publish(&self, article: &Article) -> Result<String, TwideoError>;
+}
+
+/// Telegraph API client. `TELEGRAPH_URL` can point it to a local stand-in server, the
+/// account is created on the first publish unless `TELEGRAPH_TOKEN` is set.
+struct Telegraph {
+    api_url: String,
+    access_token: OnceCell<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TelegraphResponse<T> {
+    ok: bool,
+    result: Option<T>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TelegraphAccount {
+    access_token: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct TelegraphPage {
+    url: String,
+}
+
+impl Telegraph {
+    async fn call<T: for<'de> Deserialize<'de>>(
+        &self,
+        method: &str,
+        params: Value,
+    ) -> Result<T, TwideoError> {
+        let response = reqwest::Client::new()
+            .post(format!("{}/{}", self.api_url, method))
+            .json(&params)
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<TelegraphResponse<T>>()
+            .await?;
+
+        match response {
+            TelegraphResponse {
+                ok: true,
+                result: Some(result),
+                ..
+            } => Ok(result),
+            TelegraphResponse { error, .. } => Err(TwideoError::Upstream(format!(
+                "telegraph {} failed: {}",
+                method,
+                error.unwrap_or_default()
+            ))),
+        }
+    }
+
+    async fn access_token(&self) -> Result<&str, TwideoError> {
+        self.access_token
+            .get_or_try_init(|| async {
+                if let Ok(token) = env::var("TELEGRAPH_TOKEN") {
+                    if !token.is_empty() {
+                        return Ok(token);
+                    }
+                }
+
+                log::info!("create telegraph account");
+
+                let account: TelegraphAccount = self
+                    .call("createAccount", json!({ "short_name": SHORT_NAME }))
+                    .await?;
+
+                Ok(account.access_token)
+            })
+            .await
+            .map(String::as_str)
+    }
+}
+
+#[async_trait]
+impl ArticlePublisher for Telegraph {
+    async fn publish(&self, article: &Article) -> Result<String, TwideoError> {
+        let access_token = self.access_token().await?;
+
+        let page: TelegraphPage = self
+            .call(
+                "createPage",
+                json!({
+                    "access_token": access_token,
+                    "title": article.title,
+                    "author_name": article.author_name,
+                    "author_url": article.author_url,
+                    "content": article.content,
+                }),
+            )
+            .await?;
+
+        Ok(page.url)
+    }
+}
+
+fn node(tag: &str, attrs: Map<String, Value>, children: Vec<Value>) -> Value {
+    let mut node = Map::new();
+    node.insert("tag".to_string(), Value::from(tag));
+    if !attrs.is_empty() {
+        node.insert("attrs".to_string(), Value::Object(attrs));
+    }
+    if !children.is_empty() {
+        node.insert("children".to_string(), Value::Array(children));
+    }
+    Value::Object(node)
+}
+
+/// Converts the telegram flavored HTML of the captions into Telegraph nodes. Only the
+/// tags the captions are made of are expected, so there is no need for a real parser.
+fn html_to_nodes(html: &str) -> Vec<Value> {
+    // (tag, attributes, children) of the elements that are not closed yet
+    let mut stack = vec![(String::new(), Map::new(), Vec::new())];
+    let mut rest = html;
+
+    while !rest.is_empty() {
+        if let Some(tag) = rest.strip_prefix('<') {
+            let end = match tag.find('>') {
+                Some(end) => end,
+                None => break,
+            };
+            let (tag, tail) = (&tag[..end], &tag[end + 1..]);
+            rest = tail;
+
+            if tag.starts_with('/') {
+                if stack.len() > 1 {
+                    if let Some((name, attrs, children)) = stack.pop() {
+                        stack
+                            .last_mut()
+                            .unwrap()
+                            .2
+                            .push(node(&name, attrs, children));
+                    }
+                }
+                continue;
+            }
+
+            let name = tag
+                .split_whitespace()
+                .next()
+                .unwrap_or_default()
+                .to_string();
+            let mut attrs = Map::new();
+            if let Some(href) = HREF_RE.captures(tag).and_then(|c| c.get(1)) {
+                attrs.insert(
+                    "href".to_string(),
+                    Value::from(unescape_html(href.as_str())),
+                );
+            }
+            stack.push((name, attrs, Vec::new()));
+        } else {
+            let end = rest.find('<').unwrap_or(rest.len());
+            let children = &mut stack.last_mut().unwrap().2;
+
+            for (i, line) in unescape_html(&rest[..end]).split('\n').enumerate() {
+                if i > 0 {
+                    children.push(node("br", Map::new(), vec![]));
+                }
+                if !line.is_empty() {
+                    children.push(Value::from(line));
+                }
+            }
+
+            rest = &rest[end..];
+        }
+    }
+
+    while stack.len() > 1 {
+        if let Some((name, attrs, children)) = stack.pop() {
+            stack
+                .last_mut()
+                .unwrap()
+                .2
+                .push(node(&name, attrs, children));
+        }
+    }
+
+    stack
+        .pop()
+        .map(|(_, _, children)| children)
+        .unwrap_or_default()
+}
+
+/// Lays the tweets of a thread out one after another, each with its media below the text.
+pub fn thread_article(tweets: &[TwitDetails]) -> Article {
+    let author = tweets.first();
+    let name = author.map(|tweet| tweet.name.clone()).unwrap_or_default();
+    let username = author
+        .map(|tweet| tweet.username.clone())
+        .unwrap_or_default();
+
+    let mut content = Vec::new();
+
+    for (i, tweet) in tweets.iter().enumerate() {
+        if i > 0 {
+            content.push(node("hr", Map::new(), vec![]));
+        }
+
+        content.push(node("p", Map::new(), html_to_nodes(&tweet.caption)));
+
+        for media in tweet.album() {
+            let mut attrs = Map::new();
+            if media.r#type == "photo" {
+                attrs.insert("src".to_string(), Value::from(media.url.as_str()));
+                content.push(node("figure", Map::new(), vec![node("img", attrs, vec![])]));
+            } else {
+                attrs.insert("src".to_string(), Value::from(media.thumb.as_str()));
+                let mut link = Map::new();
+                link.insert(
+                    "href".to_string(),
+                    Value::from(media.url_for(VideoQuality::Best)),
+                );
+                content.push(node(
+                    "figure",
+                    Map::new(),
+                    vec![
+                        node("img", attrs, vec![]),
+                        node(
+                            "figcaption",
+                            Map::new(),
+                            vec![node("a", link, vec![Value::from("▶️ Video")])],
+                        ),
+                    ],
+                ));
+            }
+        }
+    }
+
+    Article {
+        title: format!("Thread by {}", name)
+            .chars()
+            .take(TITLE_LIMIT)
+            .collect(),
+        author_name: name,
+        author_url: format!("https://twitter.com/{}", username),
+        content,
+    }
+}
+
+/// Threads are unrolled into plain messages when `THREAD_UNROLL` is set to `messages`.
+pub fn is_enabled() -> bool {
+    *THREAD_UNROLL != "messages"
+}
+
+pub async fn publish(article: &Article) -> Result<String, TwideoError> {
+    PUBLISHER.publish(article).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::{TwitterMedia, VideoMetadata};
+    use axum::{routing::post, Json, Router};
+
+    fn tweet(caption: &str, twitter_media: Vec<TwitterMedia>) -> TwitDetails {
+        TwitDetails {
+            caption: caption.to_string(),
+            twitter_media,
+            name: "Name".to_string(),
+            username: "user".to_string(),
+            id: 20,
+            conversation_id: 20,
+            next: 1,
+            user_id: 12,
+            thread_count: 0,
+            quoted: None,
+            replied_to: None,
+            poll: None,
+            card: None,
+        }
+    }
+
+    #[test]
+    fn html_becomes_nodes() {
+        assert_eq!(
+            html_to_nodes(
+                "<b>bold</b> &amp; <a href=\"https://x.com/?a=1&amp;b=2\">link</a>\nnext"
+            ),
+            vec![
+                json!({ "tag": "b", "children": ["bold"] }),
+                json!(" & "),
+                json!({ "tag": "a", "attrs": { "href": "https://x.com/?a=1&b=2" }, "children": ["link"] }),
+                json!({ "tag": "br" }),
+                json!("next"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unclosed_tags_are_closed() {
+        assert_eq!(
+            html_to_nodes("<i>open <b>nested"),
+            vec![
+                json!({ "tag": "i", "children": ["open ", { "tag": "b", "children": ["nested"] }] })
+            ]
+        );
+    }
+
+    #[test]
+    fn article_separates_tweets() {
+        let photo = TwitterMedia {
+            url: "https://pbs.twimg.com/media/photo.jpg".to_string(),
+            r#type: "photo".to_string(),
+            thumb: String::new(),
+            file_id: None,
+            variants: Vec::new(),
+            metadata: VideoMetadata::default(),
+            alt_text: None,
+        };
+
+        let article = thread_article(&[tweet("first", vec![photo]), tweet("second", vec![])]);
+
+        assert_eq!(article.title, "Thread by Name");
+        assert_eq!(article.author_url, "https://twitter.com/user");
+        assert_eq!(
+            article.content,
+            vec![
+                json!({ "tag": "p", "children": ["first"] }),
+                json!({ "tag": "figure", "children": [
+                    { "tag": "img", "attrs": { "src": "https://pbs.twimg.com/media/photo.jpg" } }
+                ] }),
+                json!({ "tag": "hr" }),
+                json!({ "tag": "p", "children": ["second"] }),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn publishes_to_stand_in_server() {
+        let app = Router::new()
+            .route(
+                "/createAccount",
+                post(|| async { Json(json!({ "ok": true, "result": { "access_token": "token" } })) }),
+            )
+            .route(
+                "/createPage",
+                post(|Json(params): Json<Value>| async move {
+                    assert_eq!(params["content"], json!([{ "tag": "p", "children": ["first"] }]));
+                    Json(json!({ "ok": true, "result": { "url": "https://telegra.ph/Thread-by-Name" } }))
+                }),
+            );
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let api_url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        let telegraph = Telegraph {
+            api_url,
+            access_token: OnceCell::new(),
+        };
+        let article = thread_article(&[tweet("first", vec![])]);
+
+        assert_eq!(
+            telegraph.publish(&article).await.unwrap(),
+            "https://telegra.ph/Thread-by-Name"
+        );
+    }
+}
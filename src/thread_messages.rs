@@ -0,0 +1,94 @@
+use crate::storage;
+use lru::LruCache;
+use std::{num::NonZeroUsize, sync::Mutex};
+use teloxide::types::{ChatId, MessageId};
+
+const THREAD_MESSAGES_KEY: &str = "thread_messages";
+// bots can't delete messages older than that anyway
+const THREAD_MESSAGES_TTL: u32 = 48 * 60 * 60;
+const MEMORY_CACHE_SIZE: usize = 1000;
+
+lazy_static::lazy_static! {
+    static ref MEMORY_CACHE: Mutex<LruCache<(i64, i32), Vec<i32>>> =
+        Mutex::new(LruCache::new(NonZeroUsize::new(MEMORY_CACHE_SIZE).unwrap()));
+}
+
+/// Albums can't carry a keyboard, so the thread navigation goes in a message of its own.
+/// Remembers the messages of the tweet it belongs to, so they go away with the keyboard
+/// when the thread moves on.
+pub async fn save(chat_id: ChatId, keyboard_id: MessageId, message_ids: &[MessageId]) {
+    let message_ids: Vec<i32> = message_ids.iter().map(|message_id| message_id.0).collect();
+
+    if !storage::is_configured() {
+        if let Ok(mut cache) = MEMORY_CACHE.lock() {
+            cache.put((chat_id.0, keyboard_id.0), message_ids);
+        }
+        return;
+    }
+
+    let mut con = match storage::connection().await {
+        Ok(con) => con,
+        Err(err) => {
+            log::warn!("failed to save thread messages: {}", err);
+            return;
+        }
+    };
+
+    let redis_key = format!("{}:{}:{}", THREAD_MESSAGES_KEY, chat_id.0, keyboard_id.0);
+    let payload = serde_json::to_string(&message_ids).unwrap_or_default();
+
+    let mut pipe = redis::pipe();
+    pipe.cmd("SET")
+        .arg(redis_key)
+        .arg(payload)
+        .arg("EX")
+        .arg(THREAD_MESSAGES_TTL);
+
+    let result: Result<(), _> = storage::run(pipe.query_async(&mut con)).await;
+
+    if let Err(err) = result {
+        log::warn!("failed to save thread messages: {}", err);
+    }
+}
+
+/// Messages saved with the keyboard, forgetting them.
+pub async fn take(chat_id: ChatId, keyboard_id: MessageId) -> Vec<MessageId> {
+    let message_ids: Option<Vec<i32>> = if !storage::is_configured() {
+        MEMORY_CACHE
+            .lock()
+            .ok()
+            .and_then(|mut cache| cache.pop(&(chat_id.0, keyboard_id.0)))
+    } else {
+        match storage::connection().await {
+            Ok(mut con) => {
+                let redis_key = format!("{}:{}:{}", THREAD_MESSAGES_KEY, chat_id.0, keyboard_id.0);
+                let mut pipe = redis::pipe();
+                pipe.cmd("GET").arg(redis_key.clone());
+                pipe.cmd("DEL").arg(redis_key).ignore();
+
+                let result: Result<(Option<String>,), _> =
+                    storage::run(pipe.query_async(&mut con)).await;
+
+                match result {
+                    Ok((payload,)) => {
+                        payload.and_then(|payload| serde_json::from_str(&payload).ok())
+                    }
+                    Err(err) => {
+                        log::warn!("failed to read thread messages: {}", err);
+                        None
+                    }
+                }
+            }
+            Err(err) => {
+                log::warn!("failed to read thread messages: {}", err);
+                None
+            }
+        }
+    };
+
+    message_ids
+        .unwrap_or_default()
+        .into_iter()
+        .map(MessageId)
+        .collect()
+}
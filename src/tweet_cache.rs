@@ -0,0 +1,160 @@
+use crate::{errors::TwideoError, helpers::TwitDetails, metrics, storage};
+use async_trait::async_trait;
+use lru::LruCache;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const TWEET_KEY: &str = "tweet";
+const MEMORY_CACHE_SIZE: usize = 1000;
+
+lazy_static::lazy_static! {
+    static ref TWEET_CACHE_TTL: u64 = env::var("TWEET_CACHE_TTL")
+        .ok()
+        .and_then(|ttl| ttl.parse().ok())
+        .unwrap_or(60 * 60);
+    static ref TWEET_NEGATIVE_CACHE_TTL: u64 = env::var("TWEET_NEGATIVE_CACHE_TTL")
+        .ok()
+        .and_then(|ttl| ttl.parse().ok())
+        .unwrap_or(10 * 60);
+    static ref TWEET_CACHE: Box<dyn TweetCache> = if storage::is_configured() {
+        Box::new(RedisTweetCache)
+    } else {
+        Box::new(MemoryTweetCache {
+            entries: Mutex::new(LruCache::new(NonZeroUsize::new(MEMORY_CACHE_SIZE).unwrap())),
+        })
+    };
+}
+
+/// Tweets that are known to be unavailable, cached so we don't ask twitter about them
+/// over and over again.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum MissingTweet {
+    NotFound,
+    Deleted,
+    Protected,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum CachedTweet {
+    Found(Box<TwitDetails>),
+    Missing(MissingTweet),
+}
+
+impl From<MissingTweet> for TwideoError {
+    fn from(missing: MissingTweet) -> Self {
+        match missing {
+            MissingTweet::NotFound => TwideoError::NotFound,
+            MissingTweet::Deleted => TwideoError::Deleted,
+            MissingTweet::Protected => TwideoError::Protected,
+        }
+    }
+}
+
+/// Storage for serialized `CachedTweet`s.
+#[async_trait]
+pub trait TweetCache: Send + Sync {
+    async fn get(&self, id: u64) -> Option<String>;
+
+    async fn put(&self, id: u64, payload: String, ttl: u64);
+}
+
+struct RedisTweetCache;
+
+#[async_trait]
+impl TweetCache for RedisTweetCache {
+    async fn get(&self, id: u64) -> Option<String> {
+        let mut con = storage::connection().await.ok()?;
+
+        storage::run(con.get(format!("{}:{}", TWEET_KEY, id)))
+            .await
+            .ok()?
+    }
+
+    async fn put(&self, id: u64, payload: String, ttl: u64) {
+        let mut con = match storage::connection().await {
+            Ok(con) => con,
+            Err(err) => {
+                log::warn!("failed to cache tweet: {}", err);
+                return;
+            }
+        };
+
+        let result: Result<(), _> =
+            storage::run(con.set_ex(format!("{}:{}", TWEET_KEY, id), payload, ttl as usize)).await;
+
+        if let Err(err) = result {
+            log::warn!("failed to cache tweet: {}", err);
+        }
+    }
+}
+
+struct MemoryTweetCache {
+    entries: Mutex<LruCache<u64, (Instant, String)>>,
+}
+
+#[async_trait]
+impl TweetCache for MemoryTweetCache {
+    async fn get(&self, id: u64) -> Option<String> {
+        let mut entries = self.entries.lock().ok()?;
+
+        match entries.get(&id) {
+            Some((expires_at, payload)) if *expires_at > Instant::now() => Some(payload.clone()),
+            Some(_) => {
+                entries.pop(&id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    async fn put(&self, id: u64, payload: String, ttl: u64) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.put(id, (Instant::now() + Duration::from_secs(ttl), payload));
+        }
+    }
+}
+
+pub async fn get(id: u64) -> Option<CachedTweet> {
+    if *TWEET_CACHE_TTL == 0 {
+        return None;
+    }
+
+    let payload = TWEET_CACHE.get(id).await;
+
+    metrics::cache_lookup("tweet", payload.is_some());
+
+    let payload = payload?;
+
+    match serde_json::from_str(&payload) {
+        Ok(cached) => {
+            log::info!("tweet {} exists in cache", id);
+            Some(cached)
+        }
+        Err(err) => {
+            log::warn!("failed to read cached tweet {}: {}", id, err);
+            None
+        }
+    }
+}
+
+pub async fn put(id: u64, tweet: &CachedTweet) {
+    let ttl = match tweet {
+        CachedTweet::Found(_) => *TWEET_CACHE_TTL,
+        CachedTweet::Missing(_) => *TWEET_NEGATIVE_CACHE_TTL,
+    };
+
+    if ttl == 0 {
+        return;
+    }
+
+    match serde_json::to_string(tweet) {
+        Ok(payload) => TWEET_CACHE.put(id, payload, ttl).await,
+        Err(err) => log::warn!("failed to serialize tweet {}: {}", id, err),
+    }
+}